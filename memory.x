@@ -18,5 +18,8 @@ MEMORY
   /* Origin = 0x08000000 + 4K + 256 = 0x08000000 + 0x1000 + 0x100 = 0x08001100 */
   /* Length = Total Flash - Bootloader - Metadata = 64K - 4K - 256 = 61184 bytes */
   /* FLASH : ORIGIN = 0x08001100, LENGTH = 61184  64K - 4K - 256 bytes */
+  /* The storage area at the end (MAP_FLASH_RANGE in storage.rs) is inside this region, so the */
+  /* linker doesn't stop the firmware from growing into it. StorageManager::new checks at boot */
+  /* that the firmware ends below it, using __veneer_limit from cortex-m-rt's link.x. */
   FLASH (rwx) : ORIGIN = 0x08000000, LENGTH = 64K
 }
@@ -0,0 +1,38 @@
+use defmt::Format;
+
+use crate::storage::{RingBuffer, RingItem};
+
+// Number of changes kept in flash. The whole ring is rewritten on every config write,
+// which doubles the flash wear of each setter, so keep it small.
+pub const AUDIT_LOG_LEN: usize = 4;
+
+// Serialized size of a single record: u32 key + i64 timestamp
+const RECORD_SIZE: usize = 4 + 8;
+
+/// A single config change.
+/// Keys are plain u32s in this firmware, so the key itself is stored rather than a hash of it.
+/// `ts` is in seconds since boot, like the event log.
+#[derive(Format, Clone, Copy, Debug, Default)]
+pub struct AuditRecord {
+    pub key: u32,
+    pub ts: i64,
+}
+
+/// Fixed-size ring of the most recent config changes, stored as a single map item
+pub type AuditLog = RingBuffer<AuditRecord, AUDIT_LOG_LEN>;
+
+impl RingItem for AuditRecord {
+    const SIZE: usize = RECORD_SIZE;
+
+    fn write_to(&self, buffer: &mut [u8]) {
+        buffer[..4].copy_from_slice(&self.key.to_le_bytes());
+        buffer[4..RECORD_SIZE].copy_from_slice(&self.ts.to_le_bytes());
+    }
+
+    fn read_from(buffer: &[u8]) -> Self {
+        AuditRecord {
+            key: u32::from_le_bytes(buffer[..4].try_into().unwrap()),
+            ts: i64::from_le_bytes(buffer[4..RECORD_SIZE].try_into().unwrap()),
+        }
+    }
+}
@@ -0,0 +1,139 @@
+// Passes binary values through the text CLI: `bytes` shows them as base64 and takes them back
+
+use defmt::Format;
+
+// Standard alphabet (RFC 4648), with '=' padding
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const PAD: u8 = b'=';
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Base64Error {
+    /// `dst` can't hold the result
+    BufferTooSmall,
+    /// The input is not valid padded base64
+    InvalidInput,
+}
+
+/// Encoded length of `len` bytes, including padding
+pub const fn encoded_len(len: usize) -> usize {
+    len.div_ceil(3) * 4
+}
+
+/// Encode `src` into `dst`, returning the number of bytes written
+pub const fn encode(src: &[u8], dst: &mut [u8]) -> Result<usize, Base64Error> {
+    let len = encoded_len(src.len());
+    if dst.len() < len {
+        return Err(Base64Error::BufferTooSmall);
+    }
+    // Three bytes in, four characters out, the last group may be short
+    let (mut i, mut o) = (0, 0);
+    while i < src.len() {
+        let left = src.len() - i;
+        let b1 = if left > 1 { src[i + 1] } else { 0 };
+        let b2 = if left > 2 { src[i + 2] } else { 0 };
+        let n = (src[i] as u32) << 16 | (b1 as u32) << 8 | b2 as u32;
+        let mut k = 0;
+        while k < 4 {
+            dst[o + k] = if k <= left { ALPHABET[(n >> (18 - 6 * k)) as usize & 0x3F] } else { PAD };
+            k += 1;
+        }
+        i += 3;
+        o += 4;
+    }
+    Ok(len)
+}
+
+/// Decode padded base64 from `src` into `dst`, returning the number of bytes written
+pub const fn decode(src: &[u8], dst: &mut [u8]) -> Result<usize, Base64Error> {
+    if src.len() % 4 != 0 {
+        return Err(Base64Error::InvalidInput);
+    }
+    let (mut i, mut len) = (0, 0);
+    while i < src.len() {
+        // Padding is only allowed at the very end, at most two characters.
+        // A third one fails below as a character that isn't in the alphabet.
+        let pad = if src[i + 3] != PAD { 0 } else if src[i + 2] != PAD { 1 } else { 2 };
+        if pad > 0 && i + 4 != src.len() {
+            return Err(Base64Error::InvalidInput);
+        }
+        let mut n = 0u32;
+        let mut k = 0;
+        while k < 4 - pad {
+            match decode_char(src[i + k]) {
+                Some(v) => n = n << 6 | v as u32,
+                None => return Err(Base64Error::InvalidInput),
+            }
+            k += 1;
+        }
+        n <<= 6 * pad;
+        let out = 3 - pad;
+        if dst.len() < len + out {
+            return Err(Base64Error::BufferTooSmall);
+        }
+        let mut k = 0;
+        while k < out {
+            dst[len + k] = (n >> (16 - 8 * k)) as u8;
+            k += 1;
+        }
+        len += out;
+        i += 4;
+    }
+    Ok(len)
+}
+
+const fn decode_char(c: u8) -> Option<u8> {
+    Some(match c {
+        b'A'..=b'Z' => c - b'A',
+        b'a'..=b'z' => c - b'a' + 26,
+        b'0'..=b'9' => c - b'0' + 52,
+        b'+' => 62,
+        b'/' => 63,
+        _ => return None,
+    })
+}
+
+// The test vectors of RFC 4648 section 10, both ways, checked at build time
+const _: () = {
+    const fn bytes_eq(a: &[u8], b: &[u8]) -> bool {
+        if a.len() != b.len() {
+            return false;
+        }
+        let mut i = 0;
+        while i < a.len() {
+            if a[i] != b[i] {
+                return false;
+            }
+            i += 1;
+        }
+        true
+    }
+
+    const VECTORS: [(&[u8], &[u8]); 7] = [
+        (b"", b""),
+        (b"f", b"Zg=="),
+        (b"fo", b"Zm8="),
+        (b"foo", b"Zm9v"),
+        (b"foob", b"Zm9vYg=="),
+        (b"fooba", b"Zm9vYmE="),
+        (b"foobar", b"Zm9vYmFy"),
+    ];
+    let mut i = 0;
+    while i < VECTORS.len() {
+        let (raw, encoded) = VECTORS[i];
+        let mut buf = [0u8; 8];
+        assert!(matches!(encode(raw, &mut buf), Ok(n) if bytes_eq(buf.split_at(n).0, encoded)));
+        let mut buf = [0u8; 6];
+        assert!(matches!(decode(encoded, &mut buf), Ok(n) if bytes_eq(buf.split_at(n).0, raw)));
+        i += 1;
+    }
+};
+
+// Output that doesn't fit and input that isn't base64
+const _: () = {
+    assert!(matches!(encode(b"foo", &mut [0; 3]), Err(Base64Error::BufferTooSmall)));
+    assert!(matches!(decode(b"Zm9vYg==", &mut [0; 3]), Err(Base64Error::BufferTooSmall)));
+    assert!(matches!(decode(b"Zm9", &mut [0; 3]), Err(Base64Error::InvalidInput)));
+    assert!(matches!(decode(b"Zg==Zg==", &mut [0; 3]), Err(Base64Error::InvalidInput)));
+    assert!(matches!(decode(b"Z===", &mut [0; 3]), Err(Base64Error::InvalidInput)));
+    assert!(matches!(decode(b"Zm9!", &mut [0; 3]), Err(Base64Error::InvalidInput)));
+};
@@ -0,0 +1,117 @@
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU32, Ordering};
+use defmt::info;
+use embassy_futures::join::join;
+use embassy_stm32::usart::{BufferedUart, Error};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::pipe::Pipe;
+use embassy_sync::signal::Signal;
+use embedded_io_async::{ErrorType, Read, Write};
+
+// Bytes each direction can hold. Once the RX pipe is full the bridge task stops reading,
+// and the BufferedUart RX ring fills up just like without the bridge.
+const BRIDGE_DEPTH: usize = 64;
+
+/// Decouples the CLI session from the UART: the bridge task moves received bytes into `rx`
+/// and sends whatever the session puts into `tx`. The session only sees a `ChannelStream`,
+/// so another transport (or an in-memory test) can feed it by driving the pipes instead.
+pub struct Bridge {
+    rx: Pipe<CriticalSectionRawMutex, BRIDGE_DEPTH>,
+    tx: Pipe<CriticalSectionRawMutex, BRIDGE_DEPTH>,
+    // Last receive error, reported by the next read so the line reader still sees it
+    rx_error: Mutex<CriticalSectionRawMutex, Cell<Option<Error>>>,
+    // Number of bytes taken from `tx` that have fully left the transport, wraps around
+    flushed: AtomicU32,
+    // Signaled whenever `flushed` advances
+    progress: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl Bridge {
+    pub const fn new() -> Self {
+        Self {
+            rx: Pipe::new(),
+            tx: Pipe::new(),
+            rx_error: Mutex::new(Cell::new(None)),
+            flushed: AtomicU32::new(0),
+            progress: Signal::new(),
+        }
+    }
+
+    /// The session's end of the bridge
+    pub fn stream(&self) -> ChannelStream<'_> {
+        ChannelStream { bridge: self, sent: 0 }
+    }
+}
+
+/// `Read + Write` over the pipes of a `Bridge`.
+/// Reads never return 0, the UART behind the bridge can't be closed.
+pub struct ChannelStream<'a> {
+    bridge: &'a Bridge,
+    // Number of bytes put into `tx`, `flush` waits until the bridge has flushed as many
+    sent: u32,
+}
+
+impl ErrorType for ChannelStream<'_> {
+    type Error = Error;
+}
+
+impl Read for ChannelStream<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if let Some(e) = self.bridge.rx_error.lock(|e| e.take()) {
+            return Err(e);
+        }
+        Ok(self.bridge.rx.read(buf).await)
+    }
+}
+
+impl Write for ChannelStream<'_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let n = self.bridge.tx.write(buf).await;
+        self.sent = self.sent.wrapping_add(n as u32);
+        Ok(n)
+    }
+
+    /// Resolves once everything written so far has left the UART, see `flush_and_wait` in cli.rs
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        while (self.sent.wrapping_sub(self.bridge.flushed.load(Ordering::Relaxed)) as i32) > 0 {
+            self.bridge.progress.wait().await;
+        }
+        Ok(())
+    }
+}
+
+/// Moves bytes between the UART and the pipes of `bridge`
+#[embassy_executor::task]
+pub async fn uart_bridge_task(uart: BufferedUart<'static>, bridge: &'static Bridge) {
+    let (mut uart_tx, mut uart_rx) = uart.split();
+    let receive = async {
+        let mut buf = [0u8; 32];
+        loop {
+            match uart_rx.read(&mut buf).await {
+                Ok(n) => bridge.rx.write_all(&buf[..n]).await,
+                Err(e) => bridge.rx_error.lock(|cell| cell.set(Some(e))),
+            }
+        }
+    };
+    let transmit = async {
+        let mut buf = [0u8; 32];
+        let mut taken = 0u32;
+        loop {
+            let n = bridge.tx.read(&mut buf).await;
+            if let Err(e) = uart_tx.write_all(&buf[..n]).await {
+                info!("UART write error: {}", e);
+            }
+            taken = taken.wrapping_add(n as u32);
+            // Only flush once the session is done writing, a flush waits for the last stop bit
+            if bridge.tx.is_empty() {
+                if let Err(e) = uart_tx.flush().await {
+                    info!("UART flush error: {}", e);
+                }
+                bridge.flushed.store(taken, Ordering::Relaxed);
+                bridge.progress.signal(());
+            }
+        }
+    };
+    join(receive, transmit).await;
+}
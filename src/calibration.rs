@@ -0,0 +1,142 @@
+// Only the on-chip sensors (`sensor` feature) apply these so far, more drivers will when they are added
+#![allow(dead_code)]
+
+use defmt::{Format, info};
+use embedded_storage_async::nor_flash::NorFlash as AsyncNorFlash;
+use heapless::Vec;
+use sequential_storage::map::{SerializationError, Value};
+use ufmt::{uDisplay, uwrite, uWrite, Formatter};
+
+use crate::storage::StorageManager;
+
+// Number of channels that can have a calibration stored
+pub const MAX_CAL_CHANNELS: usize = 4;
+
+// Scale of 1.0 in Q8 fixed point
+pub const SCALE_ONE_Q8: i16 = 256;
+
+// Serialized size of a single point: u8 channel + i16 offset + i16 scale
+const CAL_POINT_SIZE: usize = 1 + 2 + 2;
+
+/// Calibration of a single channel: `raw * scale_q8 / 256 + offset`
+#[derive(Format, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CalPoint {
+    pub channel: u8,
+    pub offset: i16,
+    pub scale_q8: i16,
+}
+
+impl CalPoint {
+    /// Apply the calibration to a raw reading
+    pub fn apply(&self, raw: i32) -> i32 {
+        ((raw as i64 * self.scale_q8 as i64) >> 8) as i32 + self.offset as i32
+    }
+}
+
+/// All stored calibration points, stored as a single map item
+#[derive(Clone, Debug, Default)]
+pub struct CalTable {
+    points: Vec<CalPoint, MAX_CAL_CHANNELS>,
+}
+
+impl CalTable {
+    /// Size of the table once serialized: point count followed by all slots
+    pub const SERIALIZED_SIZE: usize = 1 + MAX_CAL_CHANNELS * CAL_POINT_SIZE;
+
+    pub fn get(&self, channel: u8) -> Option<CalPoint> {
+        self.points.iter().find(|p| p.channel == channel).copied()
+    }
+
+    /// Update the point of an existing channel or add a new one.
+    /// Fails if the channel is new and the table is full.
+    pub fn set(&mut self, point: CalPoint) -> Result<(), ()> {
+        match self.points.iter_mut().find(|p| p.channel == point.channel) {
+            Some(existing) => {
+                *existing = point;
+                Ok(())
+            }
+            None => self.points.push(point).map_err(|_| ()),
+        }
+    }
+}
+
+impl<'a> Value<'a> for CalTable {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        let size = 1 + self.points.len() * CAL_POINT_SIZE;
+        if buffer.len() < size {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[0] = self.points.len() as u8;
+        for (point, chunk) in self.points.iter().zip(buffer[1..size].chunks_exact_mut(CAL_POINT_SIZE)) {
+            chunk[0] = point.channel;
+            chunk[1..3].copy_from_slice(&point.offset.to_le_bytes());
+            chunk[3..5].copy_from_slice(&point.scale_q8.to_le_bytes());
+        }
+        Ok(size)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<Self, SerializationError> {
+        let count = *buffer.first().ok_or(SerializationError::BufferTooSmall)? as usize;
+        if count > MAX_CAL_CHANNELS {
+            return Err(SerializationError::InvalidFormat);
+        }
+        if buffer.len() < 1 + count * CAL_POINT_SIZE {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        let mut table = CalTable::default();
+        for chunk in buffer[1..].chunks_exact(CAL_POINT_SIZE).take(count) {
+            table.points.push(CalPoint {
+                channel: chunk[0],
+                offset: i16::from_le_bytes([chunk[1], chunk[2]]),
+                scale_q8: i16::from_le_bytes([chunk[3], chunk[4]]),
+            }).ok();
+        }
+        Ok(table)
+    }
+}
+
+// One line per channel, as shown by the CLI `show` command
+impl uDisplay for CalTable {
+    fn fmt<W: uWrite + ?Sized>(&self, f: &mut Formatter<'_, W>) -> Result<(), W::Error> {
+        if self.points.is_empty() {
+            return uwrite!(f, "No channels calibrated\r\n");
+        }
+        for point in &self.points {
+            uwrite!(f, "Channel {}: offset {}, scale {}/256\r\n", point.channel, point.offset, point.scale_q8)?;
+        }
+        Ok(())
+    }
+}
+
+/// Calibration of `channel`, if one is stored
+pub async fn get_cal<F: AsyncNorFlash>(storage: &mut StorageManager<F>, channel: u8) -> Result<Option<CalPoint>, ()>
+where
+    F::Error: Format,
+{
+    Ok(storage.get_cal_table().await?.and_then(|table| table.get(channel)))
+}
+
+/// Store the calibration of `channel`, replacing the old one if there is any.
+/// Read-modify-write of the whole table, the caller holds the storage lock throughout.
+pub async fn set_cal<F: AsyncNorFlash>(storage: &mut StorageManager<F>, channel: u8, offset: i16, scale_q8: i16) -> Result<(), ()>
+where
+    F::Error: Format,
+{
+    let mut table = storage.get_cal_table().await?.unwrap_or_default();
+    if table.set(CalPoint { channel, offset, scale_q8 }).is_err() {
+        info!("Calibration table full, can't add channel {}", channel);
+        return Err(());
+    }
+    storage.set_cal_table(&table).await
+}
+
+/// Apply the stored calibration of `channel` to `raw`, uncalibrated channels pass through unchanged
+pub async fn apply<F: AsyncNorFlash>(storage: &mut StorageManager<F>, channel: u8, raw: i32) -> i32
+where
+    F::Error: Format,
+{
+    match get_cal(storage, channel).await {
+        Ok(Some(point)) => point.apply(raw),
+        _ => raw,
+    }
+}
@@ -1,15 +1,141 @@
 use core::mem::MaybeUninit; // Import MaybeUninit
 use defmt::{unwrap, info};
 use embassy_sync::signal::Signal;
+#[cfg(feature = "diagnostics")]
+use embassy_stm32::gpio::{AnyPin, Level, Output, Speed};
+#[cfg(not(feature = "bridge"))]
 use embassy_stm32::usart::BufferedUart;
-use embedded_io_async::{Read, Write, ErrorType};
+use embedded_io_async::{Error, ErrorKind, Read, Write, ErrorType};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::mutex::Mutex;
-use heapless::String;
-use ufmt::uwrite;
+#[cfg(feature = "diagnostics")]
+use embassy_time::TICK_HZ;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use heapless::{String, Vec};
+use ufmt::{uDisplay, uwrite};
 
 // Import the concrete types needed for the function signature
-use crate::storage::{AppState, ConcreteStorageManager};
+use crate::storage::{
+    AppState, ConcreteStorageManager, NetConfig, NumType, NumValue, DATA_BUFFER_SIZE, FLAG_ECHO, FLAG_LF, FLAG_NAMES,
+    DeviceRole, DEFAULT_PROMPT, KEY_NET_CONFIG, KEY_ROLE, PROMPT_MAX_LEN, USER_KEYS,
+};
+#[cfg(feature = "sensor")]
+use crate::storage::KEY_CALIBRATION;
+#[cfg(feature = "blob")]
+use crate::storage::{BLOB_CHUNK_SIZE, BLOB_MAX_LEN};
+#[cfg(feature = "leds")]
+use crate::leds::{self, LedMode};
+#[cfg(feature = "schema")]
+use crate::storage::SCHEMA;
+#[cfg(feature = "telemetry")]
+use crate::storage::MAX_SENSORS_INTERVAL;
+#[cfg(feature = "ram-fallback")]
+use crate::storage::ram_fallback_active;
+#[cfg(feature = "diagnostics")]
+use crate::storage::{KEY_BENCH, MAP_FLASH_SIZE};
+use crate::eventlog::{self, EVT_MODE_CHANGED};
+#[cfg(feature = "bridge")]
+use crate::bridge::ChannelStream;
+#[cfg(feature = "base64")]
+use crate::base64::{self, Base64Error};
+use crate::clocks;
+use crate::errstat::{self, Counted};
+use crate::security;
+#[cfg(feature = "sensor")]
+use crate::{calibration, sensor};
+#[cfg(feature = "diagnostics")]
+use crate::mem;
+#[cfg(feature = "diagnostics")]
+use crate::regs::{self, RegBlock};
+#[cfg(feature = "telemetry")]
+use crate::telemetry;
+
+// How long confirmation prompts wait for an answer
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Size of the blob written by `blobtest`, as large as blobs get with a short last chunk
+#[cfg(feature = "blob")]
+const BLOB_TEST_LEN: usize = BLOB_MAX_LEN - 3;
+// Scratch blob key for `blobtest`, like KEY_BENCH it's out of the way of real keys
+#[cfg(feature = "blob")]
+const BLOB_TEST_KEY: u16 = 0xFFFF;
+
+// Size of the buffer each command's output is built in before it's sent. Outputs that grow with
+// a list (`commands`, `errstat`, the optional part of `help`) are sized at compile time, so adding
+// an entry grows the buffer instead of cutting the output short. Longer fixed texts are sent on
+// their own, like the help text; 256 bytes covers every other command.
+const RESPONSE_SIZE: usize =
+    max(max(max(256, lines_len(COMMAND_NAMES)), errstat_len()), lines_len(FEATURE_HELP)) + PROMPT_MAX_LEN + 1;
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b { a } else { b }
+}
+
+// Output of a list printed one entry per line
+const fn lines_len(lines: &[&str]) -> usize {
+    let mut len = 0;
+    let mut i = 0;
+    while i < lines.len() {
+        len += lines[i].len() + "\r\n".len();
+        i += 1;
+    }
+    len
+}
+
+// Output of `errstat`: "<name>: <count> (<source>)\r\n" per counter, with up to 10 digits of count
+const fn errstat_len() -> usize {
+    let mut len = 0;
+    let mut i = 0;
+    while i < errstat::COUNTER_INFO.len() {
+        len += errstat::COUNTER_INFO[i].0.len() + errstat::COUNTER_INFO[i].1.len() + ": ".len() + 10 + " ()\r\n".len();
+        i += 1;
+    }
+    len
+}
+
+// How often a failed write is retried before the session is closed,
+// the delay between attempts grows by WRITE_RETRY_BACKOFF each time
+const WRITE_RETRIES: u32 = 3;
+const WRITE_RETRY_BACKOFF: Duration = Duration::from_millis(10);
+
+// Pins the `gpio` command must not touch, as port * 16 + pin:
+// LPUART1 TX/RX (PA2, PA3), RS485 DE (PB1), SWD (PA13, PA14) and, with `leds`, the status LEDs (PA6, PA7)
+#[cfg(feature = "diagnostics")]
+const GPIO_RESERVED: &[u8] = &[
+    2,
+    3,
+    13,
+    14,
+    16 + 1,
+    #[cfg(feature = "leds")]
+    6,
+    #[cfg(feature = "leds")]
+    7,
+];
+
+// Most erase-rewrite cycles a single `torture` run may do
+#[cfg(feature = "torture")]
+const TORTURE_MAX_CYCLES: u32 = 1000;
+
+// Largest deviation of the actual baud rate, in hundredths of a percent, that `baud` doesn't warn about
+const BAUD_MAX_ERROR: u32 = 200;
+
+// Extra time after the last byte went out before resetting, for the other end to read it
+const RESET_FLUSH_DELAY: Duration = Duration::from_millis(10);
+
+// Sampling interval of `watch` when none is given, and the longest one accepted.
+// The cap keeps the conversion to ticks within 32-bit math, which avoids pulling in u64 division.
+#[cfg(feature = "diagnostics")]
+const WATCH_DEFAULT_INTERVAL_MS: u32 = 1000;
+#[cfg(feature = "diagnostics")]
+const WATCH_MAX_INTERVAL_MS: u32 = 60_000;
+
+// Number of writes/reads done by `bench`. Every write wears the flash,
+// so the count given on the command line is capped.
+#[cfg(feature = "diagnostics")]
+const BENCH_DEFAULT_ITERATIONS: u32 = 20;
+#[cfg(feature = "diagnostics")]
+const BENCH_MAX_ITERATIONS: u32 = 100;
 
 // Declare Signal directly using const fn new()
 pub static STATE_UPDATED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
@@ -24,54 +150,865 @@ static STATE_MUTEX: Mutex<CriticalSectionRawMutex, ()> = Mutex::new(());
 // --- End unsafe static section ---
 
 
+/// Map a type tag like the `u16` in `7:u16` to a `NumType`
+fn parse_num_type(tag: &str) -> Option<NumType> {
+    Some(match tag {
+        "u8" => NumType::U8,
+        "u16" => NumType::U16,
+        "u32" => NumType::U32,
+        "i8" => NumType::I8,
+        "i16" => NumType::I16,
+        "i32" => NumType::I32,
+        "f32" => NumType::F32,
+        _ => return None,
+    })
+}
+
+/// Parse `value` as a number of type `ty`, out-of-range values are rejected
+fn parse_num(ty: NumType, value: &str) -> Option<NumValue> {
+    Some(match ty {
+        NumType::U8 => NumValue::U8(value.parse().ok()?),
+        NumType::U16 => NumValue::U16(value.parse().ok()?),
+        NumType::U32 => NumValue::U32(value.parse().ok()?),
+        NumType::I8 => NumValue::I8(value.parse().ok()?),
+        NumType::I16 => NumValue::I16(value.parse().ok()?),
+        NumType::I32 => NumValue::I32(value.parse().ok()?),
+        NumType::F32 => NumValue::F32(parse_decimal(value)?),
+    })
+}
+
+/// Parse a plain decimal like "-12.375" into an f32.
+/// core's float parser costs ~25K of flash, far more than we can spare, so only
+/// `[-]digits[.digits]` is accepted (no exponents, no inf/NaN).
+fn parse_decimal(value: &str) -> Option<f32> {
+    let (negative, value) = match value.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, value),
+    };
+    let (int, frac) = value.split_once('.').unwrap_or((value, ""));
+    if int.is_empty() && frac.is_empty() {
+        return None;
+    }
+    let mut result = 0.0f32;
+    for c in int.bytes() {
+        if !c.is_ascii_digit() {
+            return None;
+        }
+        result = result * 10.0 + (c - b'0') as f32;
+    }
+    let mut scale = 0.1f32;
+    for c in frac.bytes() {
+        if !c.is_ascii_digit() {
+            return None;
+        }
+        result += (c - b'0') as f32 * scale;
+        scale *= 0.1;
+    }
+    Some(if negative { -result } else { result })
+}
+
+#[cfg(feature = "diagnostics")]
+/// Parse a pin name like "PA6" into port * 16 + pin, only ports A to C exist on this package
+fn parse_pin(name: &str) -> Option<u8> {
+    let rest = name.strip_prefix('P').or_else(|| name.strip_prefix('p'))?;
+    let mut chars = rest.chars();
+    let port = match chars.next()?.to_ascii_uppercase() {
+        'A' => 0,
+        'B' => 1,
+        'C' => 2,
+        _ => return None,
+    };
+    let pin: u8 = chars.as_str().parse().ok()?;
+    if pin > 15 {
+        return None;
+    }
+    Some(port * 16 + pin)
+}
+
+/// Look up a feature flag's bit by name
+fn parse_flag(name: &str) -> Option<u8> {
+    FLAG_NAMES.iter().find(|(n, _)| *n == name).map(|&(_, bit)| bit)
+}
+
+/// Parse a dotted-quad IPv4 address like "192.168.1.10"
+const fn parse_ipv4(addr: &str) -> Option<[u8; 4]> {
+    let addr = addr.as_bytes();
+    let mut octets = [0u8; 4];
+    let mut i = 0;
+    let mut n = 0;
+    while n < octets.len() {
+        if n > 0 {
+            if i == addr.len() || addr[i] != b'.' {
+                return None;
+            }
+            i += 1;
+        }
+        // One to three decimal digits, at most 255
+        let start = i;
+        let mut value: u16 = 0;
+        while i < addr.len() && addr[i].is_ascii_digit() && i - start < 3 {
+            value = value * 10 + (addr[i] - b'0') as u16;
+            i += 1;
+        }
+        if i == start || value > u8::MAX as u16 {
+            return None;
+        }
+        octets[n] = value as u8;
+        n += 1;
+    }
+    if i != addr.len() {
+        return None;
+    }
+    Some(octets)
+}
+
+/// Parse a netmask, only contiguous masks like 255.255.255.0 are valid
+const fn parse_netmask(mask: &str) -> Option<[u8; 4]> {
+    let Some(octets) = parse_ipv4(mask) else {
+        return None;
+    };
+    let bits = u32::from_be_bytes(octets);
+    if bits.leading_ones() + bits.trailing_zeros() != 32 {
+        return None;
+    }
+    Some(octets)
+}
+
+// Addresses and masks that must be rejected, and a few that must not, checked at build time
+const _: () = {
+    assert!(matches!(parse_ipv4("192.168.1.10"), Some([192, 168, 1, 10])));
+    assert!(matches!(parse_ipv4("0.0.0.0"), Some([0, 0, 0, 0])));
+    assert!(matches!(parse_ipv4("255.255.255.255"), Some([255, 255, 255, 255])));
+    assert!(parse_ipv4("256.1.1.1").is_none());
+    assert!(parse_ipv4("1.2.3").is_none());
+    assert!(parse_ipv4("1.2.3.4.5").is_none());
+    assert!(parse_ipv4("1.2.3.").is_none());
+    assert!(parse_ipv4("1..3.4").is_none());
+    assert!(parse_ipv4("1.2.3.0004").is_none());
+    assert!(parse_ipv4("1.2.3.x").is_none());
+    assert!(parse_ipv4("").is_none());
+
+    assert!(matches!(parse_netmask("255.255.255.0"), Some([255, 255, 255, 0])));
+    assert!(parse_netmask("255.255.255.255").is_some());
+    assert!(parse_netmask("0.0.0.0").is_some());
+    assert!(parse_netmask("255.0.255.0").is_none());
+    assert!(parse_netmask("255.255.255.1").is_none());
+    assert!(parse_netmask("255.255.256.0").is_none());
+};
+
+// Names the key argument of `num set` and `bytes`, the keys they may write are USER_KEYS
+const USER_KEY_ARG: &str = "key (reserved, use 4096 and up)";
+
+/// Split a `<key>:<type>` argument
+fn parse_typed_key(arg: &str) -> Option<(u32, NumType)> {
+    let (key, tag) = arg.split_once(':')?;
+    Some((key.parse().ok()?, parse_num_type(tag)?))
+}
+
 /// Available CLI commands
 #[derive(Debug)]
 pub enum Command {
     Get,
+    /// `commission` is status with the commissioning state changed first
+    Status { commission: Option<bool> },
     Set { counter: u32 },
     SetMode { mode: u8 },
+    Events,
+    Flush,
+    #[cfg(feature = "audit")]
+    Audit,
+    #[cfg(feature = "diagnostics")]
+    Usage,
+    #[cfg(feature = "diagnostics")]
+    Fsck,
+    #[cfg(feature = "diagnostics")]
+    Writes,
+    #[cfg(feature = "diagnostics")]
+    Mem,
+    Json,
+    Flags,
+    SetNet { config: NetConfig },
+    /// `raw` skips decoding and always shows the bytes, as base64 that `bytes` takes back with the `base64` feature
+    Show { key: u32, raw: bool },
+    SetFlag { bit: u8, on: bool },
+    Clocks,
+    Baud,
+    Sample,
+    #[cfg(feature = "diagnostics")]
+    Regs { block: RegBlock },
+    #[cfg(feature = "diagnostics")]
+    Irqs,
+    #[cfg(feature = "diagnostics")]
+    ParseTest,
+    NumGet { key: u32, ty: NumType },
+    NumSet { key: u32, value: NumValue },
+    #[cfg(feature = "diagnostics")]
+    Watch { key: u32, ty: NumType, interval_ms: u32 },
+    #[cfg(feature = "diagnostics")]
+    Bench { iterations: u32 },
+    #[cfg(feature = "diagnostics")]
+    Gpio { pin_port: u8, high: bool },
+    SetBytes { key: u32, data: Vec<u8, 32> },
+    SetPrompt { prompt: String<PROMPT_MAX_LEN> },
+    #[cfg(feature = "blob")]
+    BlobTest,
+    Erase,
+    Defaults,
+    #[cfg(feature = "torture")]
+    Torture { cycles: u32 },
+    #[cfg(feature = "corrupt")]
+    Corrupt,
+    SetRole { role: DeviceRole },
+    ErrStat,
+    ErrStatClear,
+    #[cfg(feature = "maintenance")]
+    Maint,
+    #[cfg(feature = "maintenance")]
+    MaintDone,
+    #[cfg(feature = "maintenance")]
+    MaintInterval { hours: u32 },
+    #[cfg(feature = "leds")]
+    Led { index: usize, mode: LedMode },
+    #[cfg(feature = "schema")]
+    Schema,
+    #[cfg(feature = "lkg")]
+    Rollback,
+    #[cfg(feature = "telemetry")]
+    Telemetry,
+    #[cfg(feature = "telemetry")]
+    Interval { secs: Option<u32> },
+    Reboot,
     Help,
+    Commands,
     Unknown,
 }
 
-/// Parse a command from a string
-pub fn parse_command(input: &str) -> Command {
-    let trimmed_input = input.trim(); // Trim whitespace early
-    if trimmed_input.starts_with("get") {
-        Command::Get
-    } else if trimmed_input.starts_with("set ") {
-        // Extract counter value
-        if let Some(value_str) = trimmed_input.split_whitespace().nth(1) {
-            if let Ok(counter) = value_str.parse() {
-                return Command::Set { counter };
+/// Why a command line with a known verb couldn't be parsed
+#[derive(Debug)]
+pub enum ParseError<'a> {
+    /// A required argument is missing
+    MissingArg { which: &'static str },
+    /// An argument is present but not valid, `got` is what was given
+    BadArg { which: &'static str, got: &'a str },
+}
+
+/// Take the next argument, `which` names it in the error if it's missing
+fn next_arg<'a>(args: &mut impl Iterator<Item = &'a str>, which: &'static str) -> Result<&'a str, ParseError<'a>> {
+    args.next().ok_or(ParseError::MissingArg { which })
+}
+
+/// Take the next argument and convert it with `parse`
+fn parse_arg<'a, T>(
+    args: &mut impl Iterator<Item = &'a str>,
+    which: &'static str,
+    parse: impl FnOnce(&str) -> Option<T>,
+) -> Result<T, ParseError<'a>> {
+    let got = next_arg(args, which)?;
+    parse(got).ok_or(ParseError::BadArg { which, got })
+}
+
+/// Every command verb, sorted. `parse_command` only accepts verbs listed here (and their `ALIASES`),
+/// so this is also the list that `commands` prints for host tooling.
+pub const COMMAND_NAMES: &[&str] = &[
+    #[cfg(feature = "audit")]
+    "audit",
+    "baud",
+    #[cfg(feature = "diagnostics")]
+    "bench",
+    #[cfg(feature = "blob")]
+    "blobtest",
+    "bytes",
+    "clocks",
+    "commands",
+    "commission",
+    #[cfg(feature = "corrupt")]
+    "corrupt",
+    "defaults",
+    "erase",
+    "errstat",
+    "events",
+    "flags",
+    "flush",
+    #[cfg(feature = "diagnostics")]
+    "fsck",
+    "get",
+    #[cfg(feature = "diagnostics")]
+    "gpio",
+    "help",
+    #[cfg(feature = "telemetry")]
+    "interval",
+    #[cfg(feature = "diagnostics")]
+    "irqs",
+    "json",
+    #[cfg(feature = "leds")]
+    "led1",
+    #[cfg(feature = "leds")]
+    "led2",
+    #[cfg(feature = "maintenance")]
+    "maint",
+    #[cfg(feature = "diagnostics")]
+    "mem",
+    "mode",
+    "net",
+    "num",
+    #[cfg(feature = "diagnostics")]
+    "parsetest",
+    "prompt",
+    "reboot",
+    #[cfg(feature = "diagnostics")]
+    "regs",
+    "role",
+    #[cfg(feature = "lkg")]
+    "rollback",
+    "sample",
+    #[cfg(feature = "schema")]
+    "schema",
+    "set",
+    "show",
+    "status",
+    #[cfg(feature = "telemetry")]
+    "telemetry",
+    #[cfg(feature = "torture")]
+    "torture",
+    #[cfg(feature = "diagnostics")]
+    "usage",
+    #[cfg(feature = "diagnostics")]
+    "watch",
+    #[cfg(feature = "diagnostics")]
+    "writes",
+];
+
+// Host tooling relies on the order, keep new (and feature-gated) verbs in place
+const _: () = {
+    const fn str_lt(a: &str, b: &str) -> bool {
+        let (a, b) = (a.as_bytes(), b.as_bytes());
+        let mut i = 0;
+        while i < a.len() && i < b.len() {
+            if a[i] != b[i] {
+                return a[i] < b[i];
+            }
+            i += 1;
+        }
+        a.len() < b.len()
+    }
+
+    let mut i = 1;
+    while i < COMMAND_NAMES.len() {
+        assert!(str_lt(COMMAND_NAMES[i - 1], COMMAND_NAMES[i]), "COMMAND_NAMES is not sorted");
+        i += 1;
+    }
+};
+
+/// Familiar names from other tools, an alias as the first word is replaced by its command before parsing
+const ALIASES: &[(&str, &str)] = &[("?", "help"), ("list", "json"), ("ls", "json")];
+
+/// Parse a command from a string.
+/// Unrecognized verbs give `Command::Unknown`, bad arguments to a known verb a `ParseError`.
+pub fn parse_command(input: &str) -> Result<Command, ParseError<'_>> {
+    // The line reader only stores printable ASCII, the ASCII-only split and trims
+    // keep core's Unicode whitespace tables out of the binary
+    let mut args = input.split_ascii_whitespace();
+    let verb = args.next().unwrap_or("");
+    let verb = ALIASES.iter().find(|&&(alias, _)| alias == verb).map_or(verb, |&(_, command)| command);
+    if !COMMAND_NAMES.contains(&verb) {
+        return Ok(Command::Unknown);
+    }
+    let args = &mut args;
+    Ok(match verb {
+        "get" => Command::Get,
+        "status" => Command::Status { commission: None },
+        "set" => Command::Set { counter: parse_arg(args, "value", |a| a.parse().ok())? },
+        "mode" => Command::SetMode { mode: parse_arg(args, "value", |a| a.parse().ok())? },
+        "events" => Command::Events,
+        "flush" => Command::Flush,
+        #[cfg(feature = "audit")]
+        "audit" => Command::Audit,
+        #[cfg(feature = "diagnostics")]
+        "usage" => Command::Usage,
+        #[cfg(feature = "diagnostics")]
+        "fsck" => Command::Fsck,
+        #[cfg(feature = "diagnostics")]
+        "writes" => Command::Writes,
+        #[cfg(feature = "diagnostics")]
+        "mem" => Command::Mem,
+        "json" => Command::Json,
+        #[cfg(feature = "schema")]
+        "schema" => Command::Schema,
+        "flags" => match args.next() {
+            None => Command::Flags,
+            Some(name) => {
+                let bit = parse_flag(name).ok_or(ParseError::BadArg { which: "flag", got: name })?;
+                let on = parse_arg(args, "on|off", |a| match a {
+                    "on" => Some(true),
+                    "off" => Some(false),
+                    _ => None,
+                })?;
+                Command::SetFlag { bit, on }
+            }
+        },
+        "clocks" => Command::Clocks,
+        "show" => Command::Show { key: parse_arg(args, "key", |a| a.parse().ok())?, raw: false },
+        "baud" => Command::Baud,
+        "sample" => Command::Sample,
+        #[cfg(feature = "telemetry")]
+        "telemetry" => Command::Telemetry,
+        #[cfg(feature = "telemetry")]
+        "interval" => match args.next() {
+            None => Command::Interval { secs: None },
+            Some(a) => Command::Interval {
+                secs: Some(a.parse().ok().filter(|s| (1..=MAX_SENSORS_INTERVAL).contains(s)).ok_or(ParseError::BadArg { which: "seconds", got: a })?),
+            },
+        },
+        "net" => match args.next() {
+            None => Command::Show { key: KEY_NET_CONFIG, raw: false },
+            Some(ip) => Command::SetNet {
+                config: NetConfig {
+                    ip: parse_ipv4(ip).ok_or(ParseError::BadArg { which: "ip", got: ip })?,
+                    mask: parse_arg(args, "netmask", parse_netmask)?,
+                    gw: parse_arg(args, "gateway", parse_ipv4)?,
+                    port: parse_arg(args, "port", |a| a.parse().ok().filter(|&p| p != 0))?,
+                },
+            },
+        },
+        #[cfg(feature = "diagnostics")]
+        "regs" => Command::Regs { block: parse_arg(args, "register block", RegBlock::parse)? },
+        #[cfg(feature = "diagnostics")]
+        "irqs" => Command::Irqs,
+        #[cfg(feature = "diagnostics")]
+        "parsetest" => Command::ParseTest,
+        "num" => match next_arg(args, "subcommand")? {
+            "get" => {
+                let (key, ty) = parse_arg(args, "key:type", parse_typed_key)?;
+                Command::NumGet { key, ty }
+            }
+            "set" => {
+                let (key, ty) = parse_arg(args, USER_KEY_ARG, |a| parse_typed_key(a).filter(|(key, _)| USER_KEYS.contains(key)))?;
+                let value = parse_arg(args, "value", |a| parse_num(ty, a))?;
+                Command::NumSet { key, value }
+            }
+            other => return Err(ParseError::BadArg { which: "subcommand", got: other }),
+        },
+        #[cfg(feature = "diagnostics")]
+        "watch" => {
+            let (key, ty) = parse_arg(args, "key:type", parse_typed_key)?;
+            let interval_ms = match args.next() {
+                None => WATCH_DEFAULT_INTERVAL_MS,
+                Some(arg) => arg
+                    .parse()
+                    .ok()
+                    .filter(|ms| (1..=WATCH_MAX_INTERVAL_MS).contains(ms))
+                    .ok_or(ParseError::BadArg { which: "interval", got: arg })?,
+            };
+            Command::Watch { key, ty, interval_ms }
+        }
+        #[cfg(feature = "diagnostics")]
+        "bench" => {
+            let iterations = match args.next() {
+                None => BENCH_DEFAULT_ITERATIONS,
+                Some(arg) => arg
+                    .parse()
+                    .ok()
+                    .filter(|n| (1..=BENCH_MAX_ITERATIONS).contains(n))
+                    .ok_or(ParseError::BadArg { which: "count", got: arg })?,
+            };
+            Command::Bench { iterations }
+        }
+        #[cfg(feature = "diagnostics")]
+        "gpio" => {
+            let pin_port = parse_arg(args, "pin", parse_pin)?;
+            let high = parse_arg(args, "level", |a| match a {
+                "0" => Some(false),
+                "1" => Some(true),
+                _ => None,
+            })?;
+            Command::Gpio { pin_port, high }
+        }
+        #[cfg(feature = "blob")]
+        "blobtest" => Command::BlobTest,
+        "bytes" => {
+            let got = next_arg(args, "key")?;
+            let key = got.parse().map_err(|_| ParseError::BadArg { which: "key", got })?;
+            match args.next() {
+                None => Command::Show { key, raw: true },
+                Some(_) if !USER_KEYS.contains(&key) => return Err(ParseError::BadArg { which: USER_KEY_ARG, got }),
+                Some(arg) => {
+                    let mut data = [0u8; 32];
+                    // Base64 as `bytes <key>` shows it, or hex
+                    #[cfg(feature = "base64")]
+                    let parsed = match arg.strip_prefix("b64:") {
+                        Some(b64) => base64::decode(b64.as_bytes(), &mut data).map_err(|e| match e {
+                            Base64Error::BufferTooSmall => "base64 data (too long)",
+                            Base64Error::InvalidInput => "base64 data",
+                        }),
+                        None => parse_hex_bytes(arg, &mut data).map_err(HexError::which),
+                    };
+                    #[cfg(not(feature = "base64"))]
+                    let parsed = parse_hex_bytes(arg, &mut data).map_err(HexError::which);
+                    let len = parsed.map_err(|which| ParseError::BadArg { which, got: arg })?;
+                    Command::SetBytes { key, data: unwrap!(Vec::from_slice(&data[..len])) }
+                }
+            }
+        }
+        "prompt" => match next_arg(args, "subcommand")? {
+            "set" => {
+                let prompt = next_arg(args, "prompt")?;
+                Command::SetPrompt { prompt: String::try_from(prompt).map_err(|_| ParseError::BadArg { which: "prompt", got: prompt })? }
+            }
+            other => return Err(ParseError::BadArg { which: "subcommand", got: other }),
+        },
+        "erase" => Command::Erase,
+        "defaults" => Command::Defaults,
+        "errstat" => match args.next() {
+            None => Command::ErrStat,
+            Some("clear") => Command::ErrStatClear,
+            Some(other) => return Err(ParseError::BadArg { which: "subcommand", got: other }),
+        },
+        #[cfg(feature = "leds")]
+        "led1" | "led2" => Command::Led {
+            index: if verb == "led1" { 0 } else { 1 },
+            mode: parse_arg(args, "on|off|blink", |a| match a {
+                "on" => Some(LedMode::On),
+                "off" => Some(LedMode::Off),
+                "blink" => Some(LedMode::Blink),
+                _ => None,
+            })?,
+        },
+        #[cfg(feature = "maintenance")]
+        "maint" => match args.next() {
+            None => Command::Maint,
+            Some("done") => Command::MaintDone,
+            Some("interval") => Command::MaintInterval {
+                hours: parse_arg(args, "hours", |a| a.parse().ok().filter(|&h| h != 0))?,
+            },
+            Some(other) => return Err(ParseError::BadArg { which: "subcommand", got: other }),
+        },
+        #[cfg(feature = "torture")]
+        "torture" => Command::Torture {
+            cycles: parse_arg(args, "cycles", |a| a.parse().ok().filter(|n| (1..=TORTURE_MAX_CYCLES).contains(n)))?,
+        },
+        #[cfg(feature = "corrupt")]
+        "corrupt" => Command::Corrupt,
+        "reboot" => Command::Reboot,
+        #[cfg(feature = "lkg")]
+        "rollback" => Command::Rollback,
+        "role" => match args.next() {
+            // Shown like any other stored value
+            None => Command::Show { key: KEY_ROLE, raw: false },
+            Some(name) => Command::SetRole {
+                role: DeviceRole::from_name(name).ok_or(ParseError::BadArg { which: "role", got: name })?,
+            },
+        },
+        "commission" => match args.next() {
+            None => Command::Status { commission: Some(true) },
+            Some("clear") => Command::Status { commission: Some(false) },
+            Some(other) => return Err(ParseError::BadArg { which: "subcommand", got: other }),
+        },
+        "help" => Command::Help,
+        "commands" => Command::Commands,
+        _ => Command::Unknown,
+    })
+}
+
+/// What `parse_command` has to make of a line: `None` stands for `Command::Unknown`,
+/// otherwise it's the error's `which` and, for a `BadArg`, the argument it got
+#[cfg(feature = "diagnostics")]
+type ParseOutcome = Option<(&'static str, Option<&'static str>)>;
+
+/// Malformed command lines and their outcome, run by `parsetest`
+#[cfg(feature = "diagnostics")]
+const PARSE_CHECKS: &[(&str, ParseOutcome)] = &[
+    ("frobnicate", None),
+    ("", None),
+    ("set", Some(("value", None))),
+    ("set ten", Some(("value", Some("ten")))),
+    ("mode 256", Some(("value", Some("256")))),
+    ("flags nosuch on", Some(("flag", Some("nosuch")))),
+    ("flags echo", Some(("on|off", None))),
+    ("net 256.1.1.1", Some(("ip", Some("256.1.1.1")))),
+    ("net 10.0.0.2 255.0.255.0", Some(("netmask", Some("255.0.255.0")))),
+    ("net 10.0.0.2 255.255.255.0 10.0.0.1", Some(("port", None))),
+    ("role gateway", Some(("role", Some("gateway")))),
+    ("num", Some(("subcommand", None))),
+];
+
+/// Whether `line` parses to the outcome `expected` describes, see PARSE_CHECKS
+#[cfg(feature = "diagnostics")]
+fn parses_as(line: &str, expected: ParseOutcome) -> bool {
+    match (parse_command(line), expected) {
+        (Ok(Command::Unknown), None) => true,
+        (Err(ParseError::MissingArg { which }), Some((name, None))) => which == name,
+        (Err(ParseError::BadArg { which, got }), Some((name, Some(arg)))) => which == name && got == arg,
+        _ => false,
+    }
+}
+
+/// Why a hex argument couldn't be parsed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HexError {
+    /// A group has an odd number of digits, so it doesn't make whole bytes
+    OddLength,
+    /// Something other than a hex digit, `0x` prefix or separator
+    InvalidChar,
+    /// More bytes than fit in the output
+    TooLong,
+}
+
+impl HexError {
+    /// The argument name for `ParseError::BadArg`, saying what's wrong with it
+    fn which(self) -> &'static str {
+        match self {
+            HexError::OddLength => "hex data (odd number of digits)",
+            HexError::InvalidChar => "hex data (not a hex digit)",
+            HexError::TooLong => "hex data (too long)",
+        }
+    }
+}
+
+/// Value of a single hex digit
+const fn hex_digit(c: u8) -> Result<u8, HexError> {
+    match c {
+        b'0'..=b'9' => Ok(c - b'0'),
+        b'a'..=b'f' => Ok(c - b'a' + 10),
+        b'A'..=b'F' => Ok(c - b'A' + 10),
+        _ => Err(HexError::InvalidChar),
+    }
+}
+
+/// Parse hex into `out`, returning the number of bytes. Shared by every command taking hex,
+/// accepts e.g. "deadbeef", "de:ad:be:ef" and "0xdead". Groups are split by colons or whitespace,
+/// each may have a `0x` prefix and must have an even number of digits.
+const fn parse_hex_bytes(s: &str, out: &mut [u8]) -> Result<usize, HexError> {
+    let s = s.as_bytes();
+    let mut len = 0;
+    // Digits in the current group, and the high nibble waiting for its partner
+    let mut digits = 0;
+    let mut high = 0;
+    let mut i = 0;
+    while i < s.len() {
+        let c = s[i];
+        i += 1;
+        if c == b':' || c.is_ascii_whitespace() {
+            if digits % 2 != 0 {
+                return Err(HexError::OddLength);
             }
+            digits = 0;
+        } else if digits == 0 && c == b'0' && i < s.len() && (s[i] == b'x' || s[i] == b'X') {
+            i += 1;
+        } else {
+            let nibble = match hex_digit(c) {
+                Ok(nibble) => nibble,
+                Err(e) => return Err(e),
+            };
+            if digits % 2 == 0 {
+                high = nibble;
+            } else {
+                if len == out.len() {
+                    return Err(HexError::TooLong);
+                }
+                out[len] = high << 4 | nibble;
+                len += 1;
+            }
+            digits += 1;
+        }
+    }
+    if digits % 2 != 0 {
+        return Err(HexError::OddLength);
+    }
+    Ok(len)
+}
+
+// The accepted spellings and each error, checked at build time
+const _: () = {
+    const fn parses_to(s: &str, expected: &[u8]) -> bool {
+        let mut buf = [0u8; 8];
+        let Ok(len) = parse_hex_bytes(s, &mut buf) else {
+            return false;
+        };
+        if len != expected.len() {
+            return false;
         }
-        Command::Unknown
-    } else if trimmed_input.starts_with("mode ") {
-        // Extract mode value
-        if let Some(value_str) = trimmed_input.split_whitespace().nth(1) {
-            if let Ok(mode) = value_str.parse() {
-                return Command::SetMode { mode };
+        let mut i = 0;
+        while i < len {
+            if buf[i] != expected[i] {
+                return false;
+            }
+            i += 1;
+        }
+        true
+    }
+
+    assert!(parses_to("DEADBEEF", &[0xDE, 0xAD, 0xBE, 0xEF]));
+    assert!(parses_to("de:ad:be:ef", &[0xDE, 0xAD, 0xBE, 0xEF]));
+    assert!(parses_to("0xDEAD", &[0xDE, 0xAD]));
+    assert!(parses_to("0x01 0X02", &[0x01, 0x02]));
+    assert!(parses_to("", &[]));
+    assert!(matches!(parse_hex_bytes("DEADBEE", &mut [0; 8]), Err(HexError::OddLength)));
+    assert!(matches!(parse_hex_bytes("d:ad", &mut [0; 8]), Err(HexError::OddLength)));
+    assert!(matches!(parse_hex_bytes("DEADBEEG", &mut [0; 8]), Err(HexError::InvalidChar)));
+    assert!(matches!(parse_hex_bytes("0xx1", &mut [0; 8]), Err(HexError::InvalidChar)));
+    assert!(matches!(parse_hex_bytes("DEADBEEF", &mut [0; 3]), Err(HexError::TooLong)));
+};
+
+/// Format a number, floats are printed with 3 decimals since ufmt can't do floats.
+/// The integer part of a float is capped at u32::MAX, 32-bit math avoids pulling in u64 division.
+fn write_num<const N: usize>(out: &mut String<N>, value: NumValue) {
+    match value {
+        NumValue::U8(v) => uwrite!(out, "{}", v),
+        NumValue::U16(v) => uwrite!(out, "{}", v),
+        NumValue::U32(v) => uwrite!(out, "{}", v),
+        NumValue::I8(v) => uwrite!(out, "{}", v),
+        NumValue::I16(v) => uwrite!(out, "{}", v),
+        NumValue::I32(v) => uwrite!(out, "{}", v),
+        NumValue::F32(v) => {
+            let sign = if v < 0.0 { "-" } else { "" };
+            let v = if v < 0.0 { -v } else { v };
+            let int = v as u32;
+            let milli = ((v - int as f32) * 1000.0) as u32;
+            uwrite!(out, "{}{}.{}{}{}", sign, int, milli / 100, milli / 10 % 10, milli % 10)
+        }
+    }
+    .ok();
+}
+
+/// Write a struct read for `show`, or why there's nothing to show
+fn write_shown<const N: usize>(out: &mut String<N>, key: u32, value: Result<Option<impl uDisplay>, ()>) {
+    match value {
+        Ok(Some(value)) => uwrite!(out, "{}", value),
+        Ok(None) => uwrite!(out, "Key {} not found\r\n", key),
+        Err(_) => uwrite!(out, "Failed to read key {}\r\n", key),
+    }
+    .ok();
+}
+
+/// Write the first `len` bytes of `buf` as read by `get_bytes` in base64, in the form `bytes` takes them back
+#[cfg(feature = "base64")]
+fn write_base64<const N: usize, E>(out: &mut String<N>, key: u32, buf: &[u8], len: Result<Option<usize>, E>) {
+    match len {
+        Ok(Some(len)) => {
+            let mut encoded = [0u8; base64::encoded_len(DATA_BUFFER_SIZE)];
+            let encoded_len = unwrap!(base64::encode(&buf[..len], &mut encoded));
+            uwrite!(out, "Key {} ({} bytes): b64:", key, len).ok();
+            for &c in &encoded[..encoded_len] {
+                out.push(c as char).ok();
+            }
+            uwrite!(out, "\r\n")
+        },
+        Ok(None) => uwrite!(out, "Key {} not found\r\n", key),
+        Err(_) => uwrite!(out, "Failed to read key {}\r\n", key),
+    }
+    .ok();
+}
+
+/// Write the first `len` bytes of `buf` as read by `get_bytes`, or why there's nothing to show
+fn write_hex_dump<const N: usize, E>(out: &mut String<N>, key: u32, buf: &[u8], len: Result<Option<usize>, E>) {
+    match len {
+        Ok(Some(len)) => {
+            uwrite!(out, "Key {} ({} bytes):", key, len).ok();
+            for b in &buf[..len] {
+                uwrite!(out, " {:02x}", *b).ok();
             }
+            uwrite!(out, "\r\n")
+        },
+        Ok(None) => uwrite!(out, "Key {} not found\r\n", key),
+        Err(_) => uwrite!(out, "Failed to read key {}\r\n", key),
+    }
+    .ok();
+}
+
+/// Store a patterned blob of BLOB_TEST_LEN bytes in chunks, then check it reads back intact
+#[cfg(feature = "blob")]
+#[inline(never)]
+async fn blob_self_test(storage: &mut ConcreteStorageManager) -> bool {
+    let mut data = [0u8; BLOB_TEST_LEN];
+    for (i, b) in data.iter_mut().enumerate() {
+        *b = (i * 7 + 3) as u8;
+    }
+    let Ok(mut writer) = storage.begin_blob(BLOB_TEST_KEY).await else {
+        return false;
+    };
+    for piece in data.chunks(BLOB_CHUNK_SIZE) {
+        if storage.write_chunk(&mut writer, piece).await.is_err() {
+            return false;
         }
-        Command::Unknown
-    } else if trimmed_input == "help" {
-        Command::Help
-    } else if trimmed_input.is_empty() {
-        Command::Unknown
     }
-     else {
-        Command::Unknown
+    if storage.commit_blob(writer).await.is_err() {
+        return false;
     }
+
+    let mut read = [0u8; BLOB_TEST_LEN];
+    matches!(storage.read_blob(BLOB_TEST_KEY, &mut read).await, Ok(Some(BLOB_TEST_LEN))) && read == data
 }
 
 /// Generate help text for CLI commands
 pub fn get_help_text() -> &'static str {
     "Available commands:\r\n\
      get - Display current counter value and mode\r\n\
+     status - Show uptime, power-on hours and commissioning\r\n\
      set <value> - Set counter to <value>\r\n\
      mode <value> - Set mode to <value>\r\n\
-     help - Show this help text\r\n"
+     events - Show recent logged events\r\n\
+     flush - Write events still held in RAM to flash\r\n\
+     json - Export the stored config as JSON (alias: ls, list)\r\n\
+     flags [<name> <on|off>] - Show or change feature flags (echo: echo typed input, lf: end lines with LF instead of CRLF)\r\n\
+     net [<ip> <mask> <gw> <port>] - Show or set the static network config\r\n\
+     show <key> - Show a stored value decoded by its key, or as raw bytes\r\n\
+     clocks - Show the active clock configuration\r\n\
+     baud - Compare the configured and actual LPUART baud rate\r\n\
+     sample - Read the supply voltage and die temperature\r\n\
+     num get <key>:<type> - Show a number, <type> is u8..u32, i8..i32 or f32\r\n\
+     num set <key>:<type> <value> - Store a number under <key>, 4096 and up\r\n\
+     bytes <key> [<hex>] - Show raw bytes under <key>, or store them (<key> 4096 and up)\r\n\
+     prompt set <text> - Change the prompt, up to 16 characters without spaces\r\n\
+     erase - Erase all stored data (asks for confirmation)\r\n\
+     defaults - Reset flags, prompt, role and intervals, keeping other data (asks for confirmation)\r\n\
+     errstat [clear] - Show or reset the UART and flash error counters\r\n\
+     reboot - Restart the device\r\n\
+     role [sensor|master|repeater] - Show or change the device role, applied after a reboot\r\n\
+     commission [clear] - Mark provisioning as done, or undo it\r\n\
+     commands - List all command names, one per line\r\n\
+     help - Show this help text (alias: ?)\r\n"
+}
+
+/// Help lines of the commands that come with optional features, other than `diagnostics`
+const FEATURE_HELP: &[&str] = &[
+    #[cfg(feature = "audit")]
+    "audit - Show the last config changes",
+    #[cfg(feature = "base64")]
+    "bytes <key> b64:<base64> - Store bytes given as base64, `bytes <key>` shows them that way",
+    #[cfg(feature = "blob")]
+    "blobtest - Store a blob too large for a single value in chunks and check it reads back intact",
+    #[cfg(feature = "schema")]
+    "schema - List the config keys with their types, for host tools",
+    #[cfg(feature = "leds")]
+    "led1|led2 on|off|blink - Override a status LED, blink hands it back",
+    #[cfg(feature = "maintenance")]
+    "maint [done|interval <hours>] - Show, record or reschedule maintenance",
+    #[cfg(feature = "lkg")]
+    "rollback - Restore the last known good config, reboot to apply (asks for confirmation)",
+    #[cfg(feature = "telemetry")]
+    "interval [<seconds>] - Show or set the sensor sampling interval (max 86400), applies right away",
+    #[cfg(feature = "telemetry")]
+    "telemetry - Show the latest sensor samples, oldest first",
+    #[cfg(feature = "torture")]
+    "torture <n> - Erase and rewrite storage <n> times (max 1000), wipes the config",
+    #[cfg(feature = "corrupt")]
+    "corrupt - Break the storage so the next boot has to erase it, wipes the config",
+];
+
+/// Help text for the commands only built with the `diagnostics` feature
+#[cfg(feature = "diagnostics")]
+pub fn get_diagnostics_help_text() -> &'static str {
+    "Diagnostics:\r\n\
+     usage - Show the flash used by each stored key\r\n\
+     fsck - Check that stored data decodes, read-only\r\n\
+     writes - Show the number of writes per key since boot\r\n\
+     mem - Show the peak stack usage since boot\r\n\
+     watch <key>:<type> [<ms>] - Print a number every <ms> (default 1000, max 60000) until a keypress\r\n\
+     bench [<n>] - Time <n> (default 20, max 100) storage writes and reads\r\n\
+     gpio <pin> <0|1> - Drive a pin like PA6 as an output\r\n\
+     regs <rcc|pwr|gpioa|gpiob|gpioc> - Dump raw peripheral registers\r\n\
+     irqs - Show the NVIC state of the bound interrupts\r\n\
+     parsetest - Check that malformed commands give the right parse errors\r\n"
 }
 
 /// Initialize CLI state (UNSAFE - writes to static mut)
@@ -101,152 +1038,1037 @@ pub async fn update_state(new_state: AppState) {
 }
 
 
+/// Errors of the CLI layer. Returned by the line readers and writers,
+/// and by `run_cli_session` as the reason the session ended.
+#[derive(Debug, defmt::Format, Clone, Copy, PartialEq, Eq)]
+pub enum CliError {
+    /// Writing to the stream kept failing
+    Io,
+    /// The line didn't fit the buffer, the rest of it was discarded
+    BufferFull,
+    /// No complete line was received before the timeout expired
+    Timeout,
+    /// The other side closed the stream
+    Disconnected,
+}
+
+/// Line reader state that has to survive from one line to the next.
+/// Received bytes are read in chunks of up to RX_CHUNK_SIZE, whatever follows the end of a line
+/// is kept for the next call, so a burst with several lines doesn't lose any of them.
+/// Input that keeps arriving faster than commands are processed still has to fit in the
+/// BufferedUart RX ring (256 bytes in main.rs) while a command runs, roughly 40 ms at 57600 baud.
+///
+/// Reads are cancel-safe, which `read_line_timeout` and `watch` rely on: BufferedUart only takes
+/// bytes out of its RX ring when a read completes, and the reader's position and `rx_buf` live here
+/// rather than in the future. Dropping a `read_line` loses at most the partial line in the caller's
+/// buffer, never received bytes that follow it.
+pub struct LineReader {
+    // The last line was ended by '\r', so a '\n' right after it belongs to the same line end
+    after_cr: bool,
+    // Echo typed characters back, for terminals without local echo
+    pub echo: bool,
+    // End output lines with a bare LF instead of CRLF, for host tools that trip over the CRs
+    pub lf: bool,
+    // Bytes received but not processed yet are rx_buf[pos..len]
+    rx_buf: [u8; RX_CHUNK_SIZE],
+    pos: usize,
+    len: usize,
+}
+
+// Size of the chunks the line reader pulls from the stream
+const RX_CHUNK_SIZE: usize = 64;
+
+/// What `read_line` does with a received byte
+enum RxByte {
+    /// Part of the line: stored, or a backspace, or ignored if not printable
+    Char,
+    /// Ends the line
+    LineEnd,
+    /// The LF of a CRLF, the line already ended on the CR
+    Skip,
+}
+
+/// Classify `c`, `after_cr` says whether the byte before it was a CR
+const fn rx_byte(c: u8, after_cr: bool) -> RxByte {
+    match c {
+        b'\n' if after_cr => RxByte::Skip,
+        b'\r' | b'\n' => RxByte::LineEnd,
+        _ => RxByte::Char,
+    }
+}
+
+// A CRLF ends a single line, with no empty command after it. A lone CR or LF ends one too.
+const _: () = {
+    // The number of lines `read_line` makes of `input`, empty ones included
+    const fn count_lines(input: &[u8]) -> usize {
+        let (mut lines, mut after_cr) = (0, false);
+        let mut i = 0;
+        while i < input.len() {
+            if let RxByte::LineEnd = rx_byte(input[i], after_cr) {
+                lines += 1;
+            }
+            after_cr = input[i] == b'\r';
+            i += 1;
+        }
+        lines
+    }
+
+    assert!(count_lines(b"cmd\r\n") == 1);
+    assert!(count_lines(b"cmd\r\ncmd\r\n") == 2);
+    assert!(count_lines(b"cmd\r") == 1);
+    assert!(count_lines(b"cmd\n") == 1);
+    assert!(count_lines(b"cmd\rcmd\n") == 2);
+    // LF CR and a doubled CRLF are two line ends, the second line is empty
+    assert!(count_lines(b"cmd\n\r") == 2);
+    assert!(count_lines(b"cmd\r\n\r\n") == 2);
+    assert!(count_lines(b"cmd") == 0);
+};
+
+impl Default for LineReader {
+    fn default() -> Self {
+        Self {
+            after_cr: false,
+            echo: false,
+            lf: false,
+            rx_buf: [0; RX_CHUNK_SIZE],
+            pos: 0,
+            len: 0,
+        }
+    }
+}
+
+impl LineReader {
+    /// Read one line of input into `buf` and return it.
+    /// Only printable ASCII is stored, backspace removes the last character.
+    /// Lines may end in CR, LF or CRLF, a CRLF pair only ends a single line.
+    /// A read error (e.g. a UART overrun) discards the partial line, as some of it was lost.
+    async fn read_line<'b, T, const N: usize>(
+        &mut self,
+        stream: &mut T,
+        buf: &'b mut String<N>,
+    ) -> Result<&'b str, CliError>
+    where
+        T: Read + Write + ErrorType + ?Sized,
+        <T as ErrorType>::Error: defmt::Format + Counted,
+    {
+        buf.clear();
+        // Set once a character didn't fit, the line is reported as BufferFull when it ends
+        let mut overflow = false;
+        loop {
+            if self.pos == self.len {
+                let n = match stream.read(&mut self.rx_buf).await {
+                    Ok(n) => n,
+                    Err(e) => {
+                        info!("Error reading from stream: {:?}, discarding partial line", e);
+                        errstat::count_error(&e);
+                        buf.clear();
+                        overflow = false;
+                        continue;
+                    }
+                };
+
+                if n == 0 {
+                    info!("Stream read returned 0 bytes. Closing session.");
+                    return Err(CliError::Disconnected);
+                }
+                self.pos = 0;
+                self.len = n;
+            }
+
+            while self.pos < self.len {
+                let c = self.rx_buf[self.pos];
+                self.pos += 1;
+                let after_cr = core::mem::replace(&mut self.after_cr, c == b'\r');
+                match rx_byte(c, after_cr) {
+                    RxByte::Skip => continue,
+                    RxByte::Char => {}
+                    RxByte::LineEnd => {
+                        // Still send newline back so the terminal moves to the next line
+                        // after user presses Enter.
+                        write_out(stream, b"\r\n", self.lf).await?;
+                        if core::mem::take(&mut overflow) {
+                            return Err(CliError::BufferFull);
+                        }
+                        return Ok(buf.as_str()); // Command finished
+                    }
+                }
+                let echo: &[u8] = if c == 8 || c == 127 { // Handle backspace (BS or DEL)
+                    // Erase the character on the terminal too when echoing
+                    if buf.pop().is_some() { b"\x08 \x08" } else { b"" }
+                } else if (32..=126).contains(&c) { // Handle printable ASCII
+                    if buf.push(c as char).is_err() {
+                        if !overflow {
+                            info!("Command buffer full.");
+                        }
+                        overflow = true;
+                        b""
+                    } else {
+                        core::slice::from_ref(&c)
+                    }
+                } else {
+                    // Ignore other characters
+                    b""
+                };
+                if self.echo && !echo.is_empty() {
+                    write_all_retry(stream, echo, WRITE_RETRIES).await?;
+                }
+            }
+        }
+    }
+
+    /// Same as `read_line`, but gives up with `CliError::Timeout` if no complete line
+    /// arrives within `timeout`. Use it for prompts that must not block the session forever.
+    pub async fn read_line_timeout<'b, T, const N: usize>(
+        &mut self,
+        stream: &mut T,
+        buf: &'b mut String<N>,
+        timeout: Duration,
+    ) -> Result<&'b str, CliError>
+    where
+        T: Read + Write + ErrorType + ?Sized,
+        <T as ErrorType>::Error: defmt::Format + Counted,
+    {
+        match with_timeout(timeout, self.read_line(stream, buf)).await {
+            Ok(result) => result,
+            Err(_) => Err(CliError::Timeout),
+        }
+    }
+
+    /// Write `question` and wait up to `CONFIRM_TIMEOUT` for the answer.
+    /// Only "yes" confirms, any other answer, a line that's too long or no answer at all is a no.
+    pub async fn confirm<T>(&mut self, stream: &mut T, buf: &mut String<64>, question: &[u8]) -> Result<bool, CliError>
+    where
+        T: Read + Write + ErrorType + ?Sized,
+        <T as ErrorType>::Error: defmt::Format + Counted,
+    {
+        write_out(stream, question, self.lf).await?;
+        match self.read_line_timeout(stream, buf, CONFIRM_TIMEOUT).await {
+            Ok(answer) => Ok(answer.trim_ascii() == "yes"),
+            Err(CliError::BufferFull) => Ok(false),
+            Err(CliError::Timeout) => {
+                // The cursor is still behind the question
+                write_out(stream, b"\r\nNo answer\r\n", self.lf).await?;
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Write all of `bytes`, resending whatever is left after a partial write.
+/// Transient errors (e.g. a congested RS485 bus) are retried up to `retries` times
+/// with a growing delay. Errors that mean the other side is gone fail right away with `CliError::Disconnected`.
+pub async fn write_all_retry<T>(stream: &mut T, mut bytes: &[u8], retries: u32) -> Result<(), CliError>
+where
+    T: Write + ErrorType + ?Sized,
+    <T as ErrorType>::Error: defmt::Format,
+{
+    let mut attempt = 0;
+    while !bytes.is_empty() {
+        match stream.write(bytes).await {
+            Ok(0) => {
+                info!("Stream write returned 0 bytes");
+                return Err(CliError::Disconnected);
+            }
+            Ok(n) => bytes = &bytes[n..],
+            Err(e) => {
+                let fatal = matches!(
+                    e.kind(),
+                    ErrorKind::BrokenPipe
+                        | ErrorKind::ConnectionReset
+                        | ErrorKind::ConnectionAborted
+                        | ErrorKind::NotConnected
+                        | ErrorKind::WriteZero
+                );
+                if fatal || attempt >= retries {
+                    info!("Error writing to stream: {:?}", e);
+                    return Err(if fatal { CliError::Disconnected } else { CliError::Io });
+                }
+                attempt += 1;
+                info!("Error writing to stream: {:?}, retry {}/{}", e, attempt, retries);
+                Timer::after(WRITE_RETRY_BACKOFF * attempt).await;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write CLI output, which always uses CRLF line endings, dropping the CRs when `lf` is set.
+/// Everything the session prints goes through here so the help text and prompts follow the setting too.
+async fn write_out<T>(stream: &mut T, bytes: &[u8], lf: bool) -> Result<(), CliError>
+where
+    T: Write + ErrorType + ?Sized,
+    <T as ErrorType>::Error: defmt::Format,
+{
+    if !lf {
+        return write_all_retry(stream, bytes, WRITE_RETRIES).await;
+    }
+    for line in bytes.split(|&b| b == b'\r') {
+        write_all_retry(stream, line, WRITE_RETRIES).await?;
+    }
+    Ok(())
+}
+
+/// Wait until everything written to `stream` has actually left the UART, plus RESET_FLUSH_DELAY.
+/// Writes only queue into the BufferedUart ring, its `flush` resolves on the TC (transmission complete)
+/// interrupt after the last stop bit, which is also when the RS485 DE pin is released.
+/// Call this before any reset so the final message isn't cut off.
+pub async fn flush_and_wait<T>(stream: &mut T)
+where
+    T: Write + ErrorType + ?Sized,
+    <T as ErrorType>::Error: defmt::Format,
+{
+    if let Err(e) = stream.flush().await {
+        info!("Error flushing stream: {:?}", e);
+    }
+    Timer::after(RESET_FLUSH_DELAY).await;
+}
+
+/// One `bench` operation: store `value` under KEY_BENCH, or read it back with `None`.
+/// The lock is taken per operation so the other tasks keep running during a long run.
+#[cfg(feature = "diagnostics")]
+#[inline(never)]
+async fn bench_op(storage: &Mutex<CriticalSectionRawMutex, ConcreteStorageManager>, value: Option<u32>) -> bool {
+    let mut storage = storage.lock().await;
+    match value {
+        Some(value) => storage.set_num(KEY_BENCH, NumValue::U32(value)).await.is_ok(),
+        None => storage.get_num(KEY_BENCH, NumType::U32).await.is_ok(),
+    }
+}
+
+/// Write the events staged in RAM to flash, say so and reset
+async fn reboot<T>(stream: &mut T, storage: &Mutex<CriticalSectionRawMutex, ConcreteStorageManager>, lf: bool) -> !
+where
+    T: Write + ErrorType + ?Sized,
+    <T as ErrorType>::Error: defmt::Format,
+{
+    // Events staged in RAM would be lost by the reset
+    eventlog::flush(&mut *storage.lock().await).await.ok();
+    write_out(stream, b"Rebooting...\r\n", lf).await.ok();
+    flush_and_wait(stream).await;
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
 /// Generic function to handle the CLI session logic over any Read+Write stream.
 /// Accepts a reference to the initialized StorageManager Mutex.
+/// Only returns once the session can't go on, the error tells why.
 async fn run_cli_session<T>(
     stream: &mut T,
     storage: &'static Mutex<CriticalSectionRawMutex, ConcreteStorageManager>, // Pass storage manager mutex
-)
+) -> Result<(), CliError>
 where
     T: Read + Write + ErrorType + ?Sized,
-    <T as ErrorType>::Error: defmt::Format,
+    <T as ErrorType>::Error: defmt::Format + Counted,
 {
     // CLI buffer
-    let mut rx_buf = [0u8; 64];
+    let flags = storage.lock().await.get_flags().await.unwrap_or(0);
+    let mut reader = LineReader {
+        echo: flags & (1 << FLAG_ECHO) != 0,
+        lf: flags & (1 << FLAG_LF) != 0,
+        ..Default::default()
+    };
     let mut cmd_buf: String<64> = String::new();
-    let mut response: String<256> = String::new();
+    let mut confirm_buf: String<64> = String::new();
+    let mut response: String<RESPONSE_SIZE> = String::new();
+    let mut prompt: String<PROMPT_MAX_LEN> = match storage.lock().await.get_prompt().await {
+        Ok(Some(prompt)) => prompt,
+        _ => unwrap!(String::try_from(DEFAULT_PROMPT)),
+    };
 
     // Welcome message
     response.clear();
     uwrite!(response, "\r\n===== STM32L071 CLI =====\r\n").ok();
-    uwrite!(response, "Type 'help' for available commands\r\n> ").ok();
-    unwrap!(stream.write_all(response.as_bytes()).await);
+    uwrite!(response, "Type 'help' for available commands\r\n{} ", prompt.as_str()).ok();
+    write_out(stream, response.as_bytes(), reader.lf).await?;
 
     loop {
         // Read command
-        cmd_buf.clear();
-        'read_cmd: loop {
-            let n = match stream.read(&mut rx_buf).await {
-                Ok(n) => n,
-                Err(e) => {
-                    info!("Error reading from stream: {:?}", e);
-                    break 'read_cmd;
-                }
-            };
-
-            if n == 0 {
-                info!("Stream read returned 0 bytes. Closing session.");
-                return;
-            }
-
-            for i in 0..n {
-                let c = rx_buf[i];
-
-                // --- ECHO REMOVED ---
-                // if stream.write_all(&[c]).await.is_err() {
-                //     info!("Error writing echo to stream. Closing session.");
-                //     return;
-                // }
-                // --- END ECHO REMOVED ---
-
-                if c == b'\r' || c == b'\n' {
-                    // Still send newline back so the terminal moves to the next line
-                    // after user presses Enter.
-                    if stream.write_all(b"\r\n").await.is_err() {
-                        info!("Error writing newline to stream. Closing session.");
-                        return;
-                    }
-                    break 'read_cmd; // Command finished
-                } else if c == 8 || c == 127 { // Handle backspace (BS or DEL)
-                    // --- BACKSPACE HANDLING REMOVED ---
-                    // We only modify our internal buffer, not the terminal display
-                    if !cmd_buf.is_empty() {
-                        cmd_buf.pop();
-                    }
-                    // if stream.write_all(b"\x08 \x08").await.is_err() {
-                    //     info!("Error writing backspace sequence. Closing session.");
-                    //     return;
-                    // }
-                    // --- END BACKSPACE HANDLING REMOVED ---
-                } else if c >= 32 && c <= 126 { // Handle printable ASCII
-                    if cmd_buf.push(c as char).is_err() {
-                        info!("Command buffer full.");
-                        // Optional: Send bell or other indication?
-                    }
-                }
-                // Ignore other characters
-            }
+        match reader.read_line(stream, &mut cmd_buf).await {
+            Ok(_) => {},
+            Err(CliError::BufferFull) => {
+                response.clear();
+                uwrite!(response, "Command too long\r\n{} ", prompt.as_str()).ok();
+                write_out(stream, response.as_bytes(), reader.lf).await?;
+                continue;
+            },
+            Err(e) => return Err(e),
         }
 
-        let trimmed_cmd = cmd_buf.trim();
+        let trimmed_cmd = cmd_buf.trim_ascii();
         if trimmed_cmd.is_empty() {
             response.clear();
-            uwrite!(response, "> ").ok(); // Still send prompt
-            if stream.write_all(response.as_bytes()).await.is_err() {
-                info!("Error writing prompt. Closing session.");
-                return;
-            }
+            uwrite!(response, "{} ", prompt.as_str()).ok(); // Still send prompt
+            write_out(stream, response.as_bytes(), reader.lf).await?;
             continue;
         }
 
         info!("Processing command: {}", trimmed_cmd); // Log received command
+        // Taken once for the whole command and released with the response sent.
+        // Commands that wait for more input, the ADC or a lot of flash operations release it first,
+        // the uptime and telemetry tasks must not wait on them.
+        let mut storage_guard = storage.lock().await;
         response.clear();
 
         match parse_command(trimmed_cmd) {
-            Command::Get => {
-                let state = get_state().await; // Calls unsafe internally
-                uwrite!(response, "Counter: {}, Mode: {}\r\n", state.counter, state.mode).ok();
+            Err(ParseError::MissingArg { which }) => {
+                uwrite!(response, "Missing {}. Type 'help' for usage\r\n", which).ok();
             },
-            Command::Set { counter } => {
-                match storage.lock().await.set_counter(counter).await {
-                    Ok(_) => {
-                        let mut new_state = get_state().await; // Calls unsafe internally
-                        new_state.counter = counter;
-                        update_state(new_state).await; // Calls unsafe internally
-                        uwrite!(response, "Counter set to {}\r\n", counter).ok();
-                    },
-                    Err(_) => {
-                        uwrite!(response, "Failed to save counter\r\n").ok();
-                    }
-                }
+            Err(ParseError::BadArg { which, got }) => {
+                uwrite!(response, "Invalid {}: '{}'. Type 'help' for usage\r\n", which, got).ok();
             },
-            Command::SetMode { mode } => {
-                match storage.lock().await.set_mode(mode).await {
-                    Ok(_) => {
-                        let mut new_state = get_state().await; // Calls unsafe internally
-                        new_state.mode = mode;
-                        update_state(new_state).await; // Calls unsafe internally
-                        uwrite!(response, "Mode set to {}\r\n", mode).ok();
-                    },
-                    Err(_) => {
-                        uwrite!(response, "Failed to save mode\r\n").ok();
+            Ok(command) => match command {
+                Command::Get => {
+                    let state = get_state().await; // Calls unsafe internally
+                    uwrite!(response, "Counter: {}, Mode: {}\r\n", state.counter, state.mode).ok();
+                },
+                Command::Status { commission } => {
+                    if let Some(commissioned) = commission {
+                        if storage_guard.set_commissioned(commissioned).await.is_err() {
+                            uwrite!(response, "Failed to save commissioning\r\n").ok();
+                        }
+                    }
+                    uwrite!(response, "Uptime: {}s\r\n", Instant::now().as_secs() as u32).ok();
+                    match storage_guard.get_total_hours().await {
+                        Ok(hours) => uwrite!(response, "Total power-on hours: {}\r\n", hours).ok(),
+                        Err(_) => uwrite!(response, "Total power-on hours: unknown\r\n").ok(),
+                    };
+                    uwrite!(response, "Readout protection: {}\r\n", security::rdp_level().name()).ok();
+                    let commissioned = match storage_guard.is_commissioned().await {
+                        Ok(true) => "yes",
+                        Ok(false) => "no",
+                        Err(_) => "unknown",
+                    };
+                    uwrite!(response, "Commissioned: {}\r\n", commissioned).ok();
+                    #[cfg(feature = "ram-fallback")]
+                    if ram_fallback_active() {
+                        uwrite!(response, "Storage: DEGRADED, running on RAM, config is lost on reboot\r\n").ok();
+                    }
+                },
+                Command::Set { counter } => {
+                    match storage_guard.set_counter(counter).await {
+                        Ok(_) => {
+                            let mut new_state = get_state().await; // Calls unsafe internally
+                            new_state.counter = counter;
+                            update_state(new_state).await; // Calls unsafe internally
+                            uwrite!(response, "Counter set to {}\r\n", counter).ok();
+                        },
+                        Err(_) => {
+                            uwrite!(response, "Failed to save counter\r\n").ok();
+                        }
+                    }
+                },
+                Command::SetMode { mode } => {
+                    match storage_guard.set_mode(mode).await {
+                        Ok(_) => {
+                            eventlog::push_event(&mut storage_guard, EVT_MODE_CHANGED).await.ok();
+                            let mut new_state = get_state().await; // Calls unsafe internally
+                            new_state.mode = mode;
+                            update_state(new_state).await; // Calls unsafe internally
+                            uwrite!(response, "Mode set to {}\r\n", mode).ok();
+                        },
+                        Err(_) => {
+                            uwrite!(response, "Failed to save mode\r\n").ok();
+                        }
+                    }
+                },
+                Command::Events => {
+                    let log = eventlog::read_events(&mut *storage_guard).await;
+                    if log.is_empty() {
+                        uwrite!(response, "No events logged\r\n").ok();
+                    }
+                    for event in log.iter() {
+                        uwrite!(response, "[{}s] event {}\r\n", event.ts as u32, event.code).ok();
+                    }
+                },
+                Command::Flush => {
+                    match eventlog::flush(&mut *storage_guard).await {
+                        Ok(_) => uwrite!(response, "Events written to flash\r\n").ok(),
+                        Err(_) => uwrite!(response, "Failed to write events\r\n").ok(),
+                    };
+                },
+                #[cfg(feature = "audit")]
+                Command::Audit => {
+                    match storage_guard.read_audit().await {
+                        Ok(log) if log.is_empty() => uwrite!(response, "No config changes logged\r\n").ok(),
+                        Ok(log) => {
+                            for record in log.iter() {
+                                uwrite!(response, "[{}s] key {} changed\r\n", record.ts as u32, record.key).ok();
+                            }
+                            Some(())
+                        }
+                        Err(_) => uwrite!(response, "Failed to read audit log\r\n").ok(),
+                    };
+                },
+                #[cfg(feature = "diagnostics")]
+                Command::Usage => {
+                    let mut total = 0;
+                    let result = storage_guard.entry_sizes(|key, size| {
+                        uwrite!(response, "Key {}: {} bytes\r\n", key, size).ok();
+                        total += size;
+                    }).await;
+                    match result {
+                        Ok(_) => uwrite!(response, "{} of {} bytes used by config\r\n", total, MAP_FLASH_SIZE).ok(),
+                        Err(_) => uwrite!(response, "Failed to read storage\r\n").ok(),
+                    };
+                },
+                #[cfg(feature = "diagnostics")]
+                Command::Fsck => {
+                    let result = storage_guard.fsck(|key| {
+                        uwrite!(response, "Key {} is corrupt\r\n", key).ok();
+                    }).await;
+                    match result {
+                        Ok((entries, corrupt)) => uwrite!(response, "{} entries, {} corrupt\r\n", entries, corrupt).ok(),
+                        Err(_) => uwrite!(response, "Failed to read storage\r\n").ok(),
+                    };
+                },
+                #[cfg(feature = "diagnostics")]
+                Command::Writes => {
+                    storage_guard.write_counts(|key, count| {
+                        match key {
+                            Some(key) => uwrite!(response, "Key {}: {} writes\r\n", key, count).ok(),
+                            None => uwrite!(response, "Other keys: {} writes\r\n", count).ok(),
+                        };
+                    });
+                    if response.is_empty() {
+                        uwrite!(response, "No writes since boot\r\n").ok();
+                    }
+                },
+                Command::Json => {
+                    if storage_guard.export_json(&mut response).await.is_ok() {
+                        uwrite!(response, "\r\n").ok();
+                    } else {
+                        response.clear();
+                        uwrite!(response, "Failed to export config\r\n").ok();
+                    }
+                },
+                #[cfg(feature = "schema")]
+                Command::Schema => {
+                    drop(storage_guard);
+                    // A line at a time, all of them don't fit in `response`
+                    for entry in SCHEMA {
+                        response.clear();
+                        uwrite!(response, "{}\r\n", entry).ok();
+                        write_out(stream, response.as_bytes(), reader.lf).await?;
+                    }
+                    response.clear();
+                },
+                Command::Flags => {
+                    match storage_guard.get_flags().await {
+                        Ok(flags) => {
+                            for (name, bit) in FLAG_NAMES {
+                                let state = if flags & (1 << bit) != 0 { "on" } else { "off" };
+                                uwrite!(response, "{}: {}\r\n", name, state).ok();
+                            }
+                        },
+                        Err(_) => {
+                            uwrite!(response, "Failed to read flags\r\n").ok();
+                        }
+                    }
+                },
+                Command::SetFlag { bit, on } => {
+                    match storage_guard.set_flag(bit, on).await {
+                        Ok(_) => {
+                            match bit {
+                                FLAG_ECHO => reader.echo = on,
+                                FLAG_LF => reader.lf = on,
+                                _ => {}
+                            }
+                            uwrite!(response, "Flag updated\r\n").ok();
+                        },
+                        Err(_) => {
+                            uwrite!(response, "Failed to save flag\r\n").ok();
+                        }
+                    }
+                },
+                Command::Show { key, raw } => {
+                    // Keys with a known type are decoded, anything else is dumped as raw bytes.
+                    // Plain numbers carry no type in flash, `num get` reads them with one.
+                    match key {
+                        KEY_NET_CONFIG if !raw => write_shown(&mut response, key, storage_guard.get_net_config().await),
+                        // The calibration is only used by the on-chip sensors so far
+                        #[cfg(feature = "sensor")]
+                        KEY_CALIBRATION if !raw => write_shown(&mut response, key, storage_guard.get_cal_table().await),
+                        KEY_ROLE if !raw => {
+                            let role = storage_guard.get_role().await;
+                            write_shown(&mut response, key, role.map(Some));
+                        },
+                        _ => {
+                            let mut buf = [0u8; DATA_BUFFER_SIZE];
+                            let len = storage_guard.get_bytes(key, &mut buf).await;
+                            // `bytes` shows base64 that can be pasted back into it, `show` the more readable hex
+                            #[cfg(feature = "base64")]
+                            if raw {
+                                write_base64(&mut response, key, &buf, len);
+                            } else {
+                                write_hex_dump(&mut response, key, &buf, len);
+                            }
+                            #[cfg(not(feature = "base64"))]
+                            write_hex_dump(&mut response, key, &buf, len);
+                        }
+                    }
+                },
+                Command::SetNet { config } => {
+                    match storage_guard.set_net_config(&config).await {
+                        Ok(_) => {
+                            uwrite!(response, "Network config saved\r\n").ok();
+                        },
+                        Err(_) => {
+                            uwrite!(response, "Failed to save network config\r\n").ok();
+                        }
+                    }
+                },
+                #[cfg(feature = "diagnostics")]
+                Command::Mem => {
+                    let (used, free) = mem::stack_usage();
+                    uwrite!(response, "Stack: {} bytes used (peak), {} bytes never touched\r\n", used, free).ok();
+                },
+                Command::Sample => {
+                    drop(storage_guard);
+                    // Only builds with the `sensor` feature set up the ADC
+                    #[cfg(feature = "sensor")]
+                    match sensor::sample().await {
+                        Some(sample) => {
+                            let mut storage_guard = storage.lock().await;
+                            let vdd = calibration::apply(&mut storage_guard, sensor::CAL_CH_VDD, sample.vdd_mv).await;
+                            let temp = calibration::apply(&mut storage_guard, sensor::CAL_CH_TEMP, sample.temp_c).await;
+                            uwrite!(response, "VDD: raw {}, {} mV, corrected {} mV\r\n", sample.vref_raw, sample.vdd_mv, vdd).ok();
+                            uwrite!(response, "Temp: raw {}, {} C, corrected {} C\r\n", sample.temp_raw, sample.temp_c, temp).ok();
+                        },
+                        None => {
+                            uwrite!(response, "Sensor unavailable\r\n").ok();
+                        }
+                    }
+                    #[cfg(not(feature = "sensor"))]
+                    uwrite!(response, "Sensor unavailable in this build\r\n").ok();
+                },
+                #[cfg(feature = "telemetry")]
+                Command::Telemetry => {
+                    let recent = telemetry::recent(&mut storage_guard).await;
+                    match recent.latest() {
+                        Some(latest) => {
+                            uwrite!(response, "Latest: {}\r\n", latest).ok();
+                            for sample in recent.iter() {
+                                uwrite!(response, "{}\r\n", sample).ok();
+                            }
+                        },
+                        None => {
+                            uwrite!(response, "No samples yet, see 'interval'\r\n").ok();
+                        }
+                    }
+                },
+                #[cfg(feature = "telemetry")]
+                Command::Interval { secs: None } => {
+                    match storage_guard.get_sensors_interval().await {
+                        Ok(secs) => uwrite!(response, "Sample interval: {} s\r\n", secs),
+                        Err(_) => uwrite!(response, "Failed to read sample interval\r\n"),
+                    }
+                    .ok();
+                },
+                #[cfg(feature = "telemetry")]
+                Command::Interval { secs: Some(secs) } => {
+                    match storage_guard.set_sensors_interval(secs).await {
+                        Ok(_) => uwrite!(response, "Sample interval set to {} s\r\n", secs),
+                        Err(_) => uwrite!(response, "Failed to save sample interval\r\n"),
                     }
+                    .ok();
+                },
+                Command::Clocks => {
+                    let clocks = clocks::read();
+                    let hse = if clocks.hse_ready { "ready" } else { "not ready" };
+                    uwrite!(response, "Source: {} (HSE {})\r\n", clocks.source, hse).ok();
+                    for (name, hz) in [
+                        ("SYSCLK", clocks.sysclk),
+                        ("HCLK", clocks.hclk),
+                        ("PCLK1", clocks.pclk1),
+                        ("PCLK2", clocks.pclk2),
+                        ("LPUART", clocks.lpuart),
+                    ] {
+                        if hz % 1_000_000 == 0 {
+                            uwrite!(response, "{}: {} MHz\r\n", name, hz / 1_000_000).ok();
+                        } else {
+                            uwrite!(response, "{}: {} Hz\r\n", name, hz).ok();
+                        }
+                    }
+                },
+                Command::Baud => {
+                    let requested = clocks::LPUART_BAUD;
+                    let actual = clocks::lpuart_baud(clocks::read().lpuart);
+                    // In hundredths of a percent
+                    let error = actual.abs_diff(requested).saturating_mul(10_000) / requested;
+                    uwrite!(response, "Requested: {} baud\r\nActual: {} baud, error {}.{}{}%\r\n", requested, actual, error / 100, error / 10 % 10, error % 10).ok();
+                    if error > BAUD_MAX_ERROR {
+                        uwrite!(response, "Warning: baud rate is off by more than 2%, check the clock config\r\n").ok();
+                    }
+                },
+                #[cfg(feature = "diagnostics")]
+                Command::Regs { block } => {
+                    regs::dump(block, |name, value| {
+                        uwrite!(response, "{}: 0x{:08x}\r\n", name, value).ok();
+                    });
+                },
+                #[cfg(feature = "diagnostics")]
+                Command::Irqs => {
+                    regs::irqs(|name, enabled, priority| {
+                        uwrite!(response, "{}: {} prio {}\r\n", name, if enabled { "on" } else { "off" }, priority).ok();
+                    });
+                },
+                #[cfg(feature = "diagnostics")]
+                Command::ParseTest => {
+                    let mut failed = 0;
+                    for &(line, expected) in PARSE_CHECKS {
+                        if !parses_as(line, expected) {
+                            uwrite!(response, "Wrong result for '{}'\r\n", line).ok();
+                            failed += 1;
+                        }
+                    }
+                    uwrite!(response, "{} checks, {} failed\r\n", PARSE_CHECKS.len(), failed).ok();
+                },
+                Command::NumGet { key, ty } => {
+                    match storage_guard.get_num(key, ty).await {
+                        Ok(Some(value)) => {
+                            uwrite!(response, "Key {}: ", key).ok();
+                            write_num(&mut response, value);
+                            uwrite!(response, "\r\n").ok();
+                        },
+                        Ok(None) => {
+                            uwrite!(response, "Key {} not found\r\n", key).ok();
+                        },
+                        Err(_) => {
+                            uwrite!(response, "Failed to read key {} with that type\r\n", key).ok();
+                        }
+                    }
+                },
+                Command::NumSet { key, value } => {
+                    match storage_guard.set_num(key, value).await {
+                        Ok(_) => {
+                            uwrite!(response, "Key {} set to ", key).ok();
+                            write_num(&mut response, value);
+                            uwrite!(response, "\r\n").ok();
+                        },
+                        Err(_) => {
+                            uwrite!(response, "Failed to store key {}\r\n", key).ok();
+                        }
+                    }
+                },
+                #[cfg(feature = "diagnostics")]
+                Command::Watch { key, ty, interval_ms } => {
+                    drop(storage_guard);
+                    write_out(stream, b"Press any key to stop\r\n", reader.lf).await?;
+                    let interval = Duration::from_ticks((interval_ms * TICK_HZ as u32 / 1000) as u64);
+                    let mut key_buf = [0u8; 1];
+                    loop {
+                        response.clear();
+                        match storage.lock().await.get_num(key, ty).await {
+                            Ok(Some(value)) => {
+                                uwrite!(response, "[{}s] ", Instant::now().as_secs() as u32).ok();
+                                write_num(&mut response, value);
+                                uwrite!(response, "\r\n").ok();
+                            },
+                            Ok(None) => {
+                                uwrite!(response, "Key {} not found\r\n", key).ok();
+                            },
+                            Err(_) => {
+                                uwrite!(response, "Failed to read key {} with that type\r\n", key).ok();
+                            }
+                        }
+                        write_out(stream, response.as_bytes(), reader.lf).await?;
+                        // Wait for the next sample, any input received meanwhile stops the watch
+                        match with_timeout(interval, stream.read(&mut key_buf)).await {
+                            Err(_) => continue,
+                            Ok(Ok(n)) if n > 0 => break,
+                            Ok(Ok(_)) => return Err(CliError::Disconnected),
+                            Ok(Err(e)) => {
+                                info!("Error reading from stream: {:?}", e);
+                                errstat::count_error(&e);
+                                return Err(CliError::Io);
+                            },
+                        }
+                    }
+                    response.clear();
+                },
+                #[cfg(feature = "diagnostics")]
+                Command::Bench { iterations } => {
+                    // The scratch item can't be removed afterwards (the L0 flash doesn't support
+                    // multiwrite), it just keeps the last value and takes up one item's worth of space.
+                    drop(storage_guard);
+                    let start = Instant::now();
+                    let mut failed = false;
+                    for i in 0..iterations {
+                        if !bench_op(storage, Some(i)).await {
+                            failed = true;
+                            break;
+                        }
+                    }
+                    let write_us = start.elapsed().as_micros();
+                    let start = Instant::now();
+                    for _ in 0..iterations {
+                        if failed || !bench_op(storage, None).await {
+                            failed = true;
+                            break;
+                        }
+                    }
+                    let read_us = start.elapsed().as_micros();
+                    if failed {
+                        uwrite!(response, "Benchmark failed\r\n").ok();
+                    } else {
+                        for (phase, us) in [("Write", write_us), ("Read", read_us)] {
+                            // 32-bit math is plenty with the capped iteration count and avoids pulling in u64 division
+                            let us = us.clamp(1, u32::MAX as u64) as u32;
+                            let ops_per_sec = iterations * 1_000_000 / us;
+                            let avg_us = us / iterations;
+                            uwrite!(response, "{}: {} ops in {} us, {} ops/s, avg {} us\r\n", phase, iterations, us, ops_per_sec, avg_us).ok();
+                        }
+                    }
+                },
+                #[cfg(feature = "diagnostics")]
+                Command::Gpio { pin_port, high } => {
+                    if GPIO_RESERVED.contains(&pin_port) {
+                        uwrite!(response, "Pin is used by a peripheral, refusing to drive it\r\n").ok();
+                    } else {
+                        // SAFETY: reserved pins are excluded above, any other pin is free for bring-up testing.
+                        // The pin is forgotten so it keeps driving the level after the command returns.
+                        let pin = unsafe { AnyPin::steal(pin_port) };
+                        let level = if high { Level::High } else { Level::Low };
+                        core::mem::forget(Output::new(pin, level, Speed::Low));
+                        uwrite!(response, "P{}{} set to {}\r\n", (b'A' + pin_port / 16) as char, pin_port % 16, high as u8).ok();
+                    }
+                },
+                Command::SetBytes { key, data } => {
+                    match storage_guard.set_bytes(key, &data).await {
+                        Ok(_) => {
+                            uwrite!(response, "Stored {} bytes under key {}\r\n", data.len(), key).ok();
+                        },
+                        Err(_) => {
+                            uwrite!(response, "Failed to store key {}\r\n", key).ok();
+                        }
+                    }
+                },
+                Command::SetPrompt { prompt: new_prompt } => {
+                    match storage_guard.set_prompt(&new_prompt).await {
+                        Ok(_) => {
+                            prompt = new_prompt;
+                            uwrite!(response, "Prompt set\r\n").ok();
+                        },
+                        Err(_) => {
+                            uwrite!(response, "Failed to store prompt\r\n").ok();
+                        }
+                    }
+                },
+                #[cfg(feature = "blob")]
+                Command::BlobTest => {
+                    // Each run leaves its chunks stored, in the bank the next run doesn't write.
+                    // Both banks are counted in the storage budget, see LIVE_DATA_SIZE.
+                    if blob_self_test(&mut storage_guard).await {
+                        uwrite!(response, "Blob of {} bytes stored and read back intact\r\n", BLOB_TEST_LEN).ok();
+                    } else {
+                        uwrite!(response, "Blob test failed\r\n").ok();
+                    }
+                },
+                Command::Erase => {
+                    // Show what would be lost before asking
+                    let preview = storage_guard.erase_preview().await;
+                    drop(storage_guard);
+                    if let Ok((keys, bytes)) = preview {
+                        uwrite!(response, "This erases {} keys, {} bytes. ", keys, bytes).ok();
+                    }
+                    uwrite!(response, "Erase all stored data? Type 'yes' to confirm: ").ok();
+                    let confirmed = reader.confirm(stream, &mut confirm_buf, response.as_bytes()).await?;
+                    response.clear();
+                    if !confirmed {
+                        uwrite!(response, "Erase cancelled\r\n").ok();
+                    } else {
+                        match storage.lock().await.erase_map_area().await {
+                            Ok(_) => {
+                                update_state(AppState::default()).await;
+                                uwrite!(response, "Storage erased\r\n").ok();
+                            },
+                            Err(_) => {
+                                uwrite!(response, "Failed to erase storage\r\n").ok();
+                            }
+                        }
+                    }
+                },
+                #[cfg(feature = "torture")]
+                Command::Torture { cycles } => {
+                    drop(storage_guard);
+                    // There's only the one storage area, so this wears and wipes the live config.
+                    // Each cycle writes the counter and mode back, everything else is lost.
+                    let question = b"Erase and rewrite all stored data repeatedly? Config other than counter and mode is lost. Type 'yes' to confirm: ";
+                    if !reader.confirm(stream, &mut confirm_buf, question).await? {
+                        uwrite!(response, "Torture test cancelled\r\n").ok();
+                    } else {
+                            let state = get_state().await;
+                            let mut storage = storage.lock().await;
+                            let (mut min_us, mut max_us, mut total_us, mut errors) = (u32::MAX, 0u32, 0u32, 0u32);
+                            for _ in 0..cycles {
+                                let start = Instant::now();
+                                let ok = storage.erase_map_area().await.is_ok()
+                                    && storage.set_counter(state.counter).await.is_ok()
+                                    && storage.set_mode(state.mode).await.is_ok();
+                                // 32-bit math, like `bench`, avoids pulling in u64 division
+                                let us = start.elapsed().as_micros().min(u32::MAX as u64) as u32;
+                                if !ok {
+                                    errors += 1;
+                                }
+                                min_us = min_us.min(us);
+                                max_us = max_us.max(us);
+                                total_us = total_us.saturating_add(us);
+                            }
+                            uwrite!(response, "{} cycles, {} errors, min {} us, max {} us, avg {} us\r\n", cycles, errors, min_us, max_us, total_us / cycles).ok();
+                    }
+                },
+                #[cfg(feature = "corrupt")]
+                Command::Corrupt => {
+                    drop(storage_guard);
+                    let question = b"Corrupt the storage? Everything stored is lost at the next boot. Type 'yes' to confirm: ";
+                    if !reader.confirm(stream, &mut confirm_buf, question).await? {
+                        uwrite!(response, "Corrupt cancelled\r\n").ok();
+                    } else if storage.lock().await.corrupt_map_area().await.is_ok() {
+                        uwrite!(response, "Storage corrupted, reboot to check that it recovers\r\n").ok();
+                    } else {
+                        uwrite!(response, "Error corrupting storage\r\n").ok();
+                    }
+                },
+                Command::Reboot => {
+                    drop(storage_guard);
+                    reboot(stream, storage, reader.lf).await
+                },
+                Command::Defaults => {
+                    drop(storage_guard);
+                    let question = b"Reset all settings to their defaults? Type 'yes' to confirm: ";
+                    if !reader.confirm(stream, &mut confirm_buf, question).await? {
+                        uwrite!(response, "Reset cancelled\r\n").ok();
+                    } else {
+                        match storage.lock().await.reset_settings().await {
+                            Ok(keys) => {
+                                reader.echo = false;
+                                reader.lf = false;
+                                prompt.clear();
+                                prompt.push_str(DEFAULT_PROMPT).ok();
+                                uwrite!(response, "{} settings reset to defaults\r\n", keys).ok();
+                            },
+                            Err(_) => {
+                                uwrite!(response, "Failed to reset settings\r\n").ok();
+                            }
+                        }
+                    }
+                },
+                #[cfg(feature = "lkg")]
+                Command::Rollback => {
+                    drop(storage_guard);
+                    let question = b"Restore the last known good config? Type 'yes' to confirm: ";
+                    if !reader.confirm(stream, &mut confirm_buf, question).await? {
+                        uwrite!(response, "Rollback cancelled\r\n").ok();
+                    } else {
+                        match storage.lock().await.restore_good().await {
+                            Ok((0, _)) => uwrite!(response, "No last known good config saved yet\r\n").ok(),
+                            Ok((_, 0)) => uwrite!(response, "Config already up to date\r\n").ok(),
+                            // Flags, prompt and role are only read at startup, a reboot applies them all
+                            Ok((_, keys)) => uwrite!(response, "{} keys restored, reboot to apply them\r\n", keys).ok(),
+                            Err(_) => uwrite!(response, "Failed to restore config\r\n").ok(),
+                        };
+                    }
+                },
+                Command::ErrStat => {
+                    for ((name, source), count) in errstat::COUNTER_INFO.iter().zip(errstat::counts()) {
+                        uwrite!(response, "{}: {} ({})\r\n", *name, count, *source).ok();
+                    }
+                },
+                Command::ErrStatClear => {
+                    errstat::clear();
+                    uwrite!(response, "Error counters cleared\r\n").ok();
+                },
+                #[cfg(feature = "leds")]
+                Command::Led { index, mode } => {
+                    leds::set_mode(index, mode);
+                    uwrite!(response, "LED{} set to {}\r\n", index + 1, mode.name()).ok();
+                },
+                #[cfg(feature = "maintenance")]
+                Command::Maint => {
+                    match storage_guard.maintenance_remaining().await {
+                        Ok(Some(hours)) if hours > 0 => uwrite!(response, "Maintenance due in {} h\r\n", hours),
+                        Ok(Some(hours)) => uwrite!(response, "Maintenance overdue by {} h\r\n", -hours),
+                        Ok(None) => uwrite!(response, "No maintenance recorded yet, run 'maint done' after servicing\r\n"),
+                        // Without the power-on hours there's no telling whether it's due
+                        Err(_) => uwrite!(response, "Maintenance due: unknown\r\n"),
+                    }
+                    .ok();
+                    if let Ok(interval) = storage_guard.get_maint_interval().await {
+                        uwrite!(response, "Interval: {} h\r\n", interval).ok();
+                    }
+                },
+                #[cfg(feature = "maintenance")]
+                Command::MaintDone => {
+                    match storage_guard.maintenance_done().await {
+                        Ok(interval) => uwrite!(response, "Next maintenance in {} h\r\n", interval),
+                        Err(_) => uwrite!(response, "Failed to save maintenance\r\n"),
+                    }
+                    .ok();
+                },
+                #[cfg(feature = "maintenance")]
+                Command::MaintInterval { hours } => {
+                    match storage_guard.set_maint_interval(hours).await {
+                        Ok(_) => uwrite!(response, "Maintenance interval set to {} h, applies from the next 'maint done'\r\n", hours),
+                        Err(_) => uwrite!(response, "Failed to save maintenance interval\r\n"),
+                    }
+                    .ok();
+                },
+                Command::SetRole { role } => {
+                    let saved = storage_guard.set_role(role).await.is_ok();
+                    drop(storage_guard);
+                    if !saved {
+                        uwrite!(response, "Failed to save role\r\n").ok();
+                    } else {
+                        // The role is only read at startup
+                        let question = b"Role saved, reboot now to apply it? Type 'yes' to confirm: ";
+                        if reader.confirm(stream, &mut confirm_buf, question).await? {
+                            reboot(stream, storage, reader.lf).await
+                        }
+                        uwrite!(response, "The new role applies after the next reboot\r\n").ok();
+                    }
+                },
+                Command::Help => {
+                    drop(storage_guard);
+                    // The help text has outgrown `response`, so it's sent on its own
+                    write_out(stream, get_help_text().as_bytes(), reader.lf).await?;
+                    for line in FEATURE_HELP {
+                        uwrite!(response, "{}\r\n", *line).ok();
+                    }
+                    #[cfg(feature = "diagnostics")]
+                    write_out(stream, get_diagnostics_help_text().as_bytes(), reader.lf).await?;
+                },
+                Command::Commands => {
+                    for name in COMMAND_NAMES {
+                        uwrite!(response, "{}\r\n", *name).ok();
+                    }
+                },
+                Command::Unknown => {
+                    uwrite!(response, "Unknown command: '{}'. Type 'help' for available commands\r\n", trimmed_cmd).ok();
                 }
             },
-            Command::Help => {
-                uwrite!(response, "{}", get_help_text()).ok();
-            },
-            Command::Unknown => {
-                uwrite!(response, "Unknown command: '{}'. Type 'help' for available commands\r\n", trimmed_cmd).ok();
-            }
         }
 
         // Add the prompt for the next command
-        uwrite!(response, "> ").ok();
-        if stream.write_all(response.as_bytes()).await.is_err() {
-            info!("Error writing response. Closing session.");
-            return;
-        }
+        uwrite!(response, "{} ", prompt.as_str()).ok();
+        write_out(stream, response.as_bytes(), reader.lf).await?;
     }
 }
 
+// What the CLI task runs its session on
+#[cfg(feature = "bridge")]
+type CliStream = ChannelStream<'static>;
+#[cfg(not(feature = "bridge"))]
+type CliStream = BufferedUart<'static>;
+
 #[embassy_executor::task]
 pub async fn cli_task(
-    mut uart: BufferedUart<'static>,
+    mut uart: CliStream,
     storage: &'static Mutex<CriticalSectionRawMutex, ConcreteStorageManager>,
 ) {
     info!("CLI Task started.");
-    run_cli_session(&mut uart, storage).await;
+    if let Err(e) = run_cli_session(&mut uart, storage).await {
+        info!("CLI session ended: {:?}", e);
+    }
     info!("CLI Task finished.");
 }
 
@@ -0,0 +1,120 @@
+use embassy_stm32::pac;
+use embassy_stm32::pac::rcc::vals::{Sw, Uartsel};
+use embassy_stm32::time::Hertz;
+
+// Frequency of the external crystal on the board
+pub const HSE_FREQ: Hertz = Hertz::mhz(16);
+
+// Baud rate the LPUART (the CLI) is configured for
+pub const LPUART_BAUD: u32 = 57600;
+
+const HSI_FREQ: u32 = 16_000_000;
+const LSE_FREQ: u32 = 32_768;
+
+// PCLK1 as set up in main.rs: HSE * 4 / 2 through the PLL, no APB prescaler
+const PCLK1_FREQ: u32 = HSE_FREQ.0 * 4 / 2;
+
+// The LPUART divisor BRR = 256 * clock / baud must be at least 0x300 and fit in 20 bits (RM0377, LPUART_BRR)
+const LPUART_BRR_MIN: u64 = 0x300;
+const LPUART_BRR_MAX: u64 = 0xF_FFFF;
+
+/// LPUART kernel clock able to generate `LPUART_BAUD`, checked at compile time
+pub const LPUART_CLOCK: Uartsel = match lpuart_clock_for(LPUART_BAUD) {
+    Some(clock) => clock,
+    None => panic!("LPUART_BAUD can't be generated from any LPUART clock source"),
+};
+
+/// Pick a kernel clock for the LPUART that can generate `baud`, preferring PCLK1.
+/// PCLK1 reaches down to about 7.8 kbaud and HSI16 to 3.9 kbaud, anything slower
+/// needs the LSE crystal (up to 10.9 kbaud), which main.rs then has to start.
+/// Only meant for const evaluation, the 64-bit division is too big to keep in flash.
+const fn lpuart_clock_for(baud: u32) -> Option<Uartsel> {
+    let sources = [(Uartsel::PCLK1, PCLK1_FREQ), (Uartsel::HSI, HSI_FREQ), (Uartsel::LSE, LSE_FREQ)];
+    let mut i = 0;
+    while i < sources.len() {
+        let brr = 256 * sources[i].1 as u64 / baud as u64;
+        if brr >= LPUART_BRR_MIN && brr <= LPUART_BRR_MAX {
+            return Some(sources[i].0);
+        }
+        i += 1;
+    }
+    None
+}
+
+// PLLMUL field value -> multiplication factor
+const PLL_MUL: [u32; 9] = [3, 4, 6, 8, 12, 16, 24, 32, 48];
+
+/// Clock tree as actually configured in the RCC registers
+pub struct ClockInfo {
+    pub source: &'static str,
+    pub hse_ready: bool,
+    pub sysclk: u32,
+    pub hclk: u32,
+    pub pclk1: u32,
+    pub pclk2: u32,
+    pub lpuart: u32,
+}
+
+/// Read the active clock configuration back from the RCC registers.
+/// Unlike `embassy_stm32::Config`, this shows what the hardware is really running on,
+/// e.g. if the HSE failed to start.
+pub fn read() -> ClockInfo {
+    let rcc = pac::RCC;
+    let cr = rcc.cr().read();
+    let cfgr = rcc.cfgr().read();
+
+    let hsi = if cr.hsidivf() { HSI_FREQ / 4 } else { HSI_FREQ };
+    let (source, sysclk) = match cfgr.sws() {
+        Sw::MSI => ("MSI", 65_536 << rcc.icscr().read().msirange().to_bits()),
+        Sw::HSI => ("HSI", hsi),
+        Sw::HSE => ("HSE", HSE_FREQ.0),
+        Sw::PLL1_R => {
+            let pll_in = if cfgr.pllsrc().to_bits() == 0 { hsi } else { HSE_FREQ.0 };
+            let mul = PLL_MUL.get(cfgr.pllmul().to_bits() as usize).copied().unwrap_or(0);
+            let div = cfgr.plldiv().to_bits() as u32 + 1;
+            ("PLL", pll_in * mul / div)
+        }
+    };
+
+    // HPRE: 0xxx = /1, 1000..1011 = /2../16, 1100..1111 = /64../512
+    let hpre = cfgr.hpre().to_bits() as u32;
+    let hclk = match hpre {
+        0..=7 => sysclk,
+        8..=11 => sysclk >> (hpre - 7),
+        _ => sysclk >> (hpre - 6),
+    };
+    // PPRE: 0xx = /1, 100..111 = /2../16
+    let apb = |ppre: u32| if ppre < 4 { hclk } else { hclk >> (ppre - 3) };
+    let pclk1 = apb(cfgr.ppre1().to_bits() as u32);
+    let pclk2 = apb(cfgr.ppre2().to_bits() as u32);
+
+    let lpuart = match rcc.ccipr().read().lpuart1sel() {
+        Uartsel::PCLK1 => pclk1,
+        Uartsel::SYS => sysclk,
+        Uartsel::HSI => hsi,
+        Uartsel::LSE => LSE_FREQ,
+    };
+
+    ClockInfo {
+        source,
+        hse_ready: cr.hserdy(),
+        sysclk,
+        hclk,
+        pclk1,
+        pclk2,
+        lpuart,
+    }
+}
+
+/// Baud rate the LPUART is really running at, back-computed from the BRR divisor
+/// and the LPUART kernel clock (`ClockInfo::lpuart`): baud = 256 * clock / BRR.
+/// The divisor only approximates the requested rate, more so at low kernel clocks.
+pub fn lpuart_baud(lpuart_clk: u32) -> u32 {
+    // BRR is 20 bits wide on the LPUART, the PAC accessor only covers the low 16 of the USART
+    let brr = pac::LPUART1.brr().read().0 & 0xF_FFFF;
+    if brr == 0 {
+        return 0;
+    }
+    // Split up so 256 * clock doesn't overflow 32 bits
+    lpuart_clk / brr * 256 + lpuart_clk % brr * 256 / brr
+}
@@ -0,0 +1,70 @@
+use core::cell::Cell;
+use embassy_stm32::usart;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+
+/// Error counters, kept in RAM since boot to tell a noisy bus from a firmware bug
+#[derive(Clone, Copy)]
+pub enum ErrCounter {
+    Framing,
+    Noise,
+    Overrun,
+    Parity,
+    FlashRead,
+    FlashWrite,
+}
+
+const COUNTERS: usize = 6;
+
+/// Name of each counter and where its errors come from, in `ErrCounter` order
+pub const COUNTER_INFO: [(&str, &str); COUNTERS] = [
+    ("uart framing", "stop bit missing: baud mismatch or bus noise"),
+    ("uart noise", "noisy samples within a bit"),
+    ("uart overrun", "byte lost, the CLI didn't read fast enough"),
+    ("uart parity", "parity bit mismatch"),
+    ("flash read", "flash driver error while reading storage"),
+    ("flash write", "flash driver error while writing storage, retries included"),
+];
+
+static COUNTS: Mutex<CriticalSectionRawMutex, Cell<[u32; COUNTERS]>> = Mutex::new(Cell::new([0; COUNTERS]));
+
+/// Errors that fall into one of the counters
+pub trait Counted {
+    fn counter(&self) -> Option<ErrCounter>;
+}
+
+impl Counted for usart::Error {
+    fn counter(&self) -> Option<ErrCounter> {
+        match self {
+            usart::Error::Framing => Some(ErrCounter::Framing),
+            usart::Error::Noise => Some(ErrCounter::Noise),
+            usart::Error::Overrun => Some(ErrCounter::Overrun),
+            usart::Error::Parity => Some(ErrCounter::Parity),
+            _ => None,
+        }
+    }
+}
+
+pub fn count(counter: ErrCounter) {
+    COUNTS.lock(|counts| {
+        let mut c = counts.get();
+        c[counter as usize] = c[counter as usize].saturating_add(1);
+        counts.set(c);
+    });
+}
+
+/// Count `error` if it falls into one of the counters
+pub fn count_error(error: &impl Counted) {
+    if let Some(counter) = error.counter() {
+        count(counter);
+    }
+}
+
+/// All counters, in `ErrCounter` order
+pub fn counts() -> [u32; COUNTERS] {
+    COUNTS.lock(|counts| counts.get())
+}
+
+pub fn clear() {
+    COUNTS.lock(|counts| counts.set([0; COUNTERS]));
+}
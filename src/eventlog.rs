@@ -0,0 +1,137 @@
+use core::cell::Cell;
+use defmt::{Format, info};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::Instant;
+use heapless::Vec;
+use embedded_storage_async::nor_flash::NorFlash as AsyncNorFlash;
+
+use crate::storage::{RingBuffer, RingItem, StorageManager};
+
+// Event codes recorded in the log
+pub const EVT_BOOT: u16 = 1;
+pub const EVT_MODE_CHANGED: u16 = 2;
+
+// Number of events kept in flash. The whole ring is rewritten on every push,
+// so keep this small to bound both the item size and the flash wear.
+pub const EVENT_LOG_LEN: usize = 4;
+
+// An identical event pushed again within this many seconds is dropped
+const DEBOUNCE_SECS: i64 = 5;
+
+// Serialized size of a single event: u16 code + i64 timestamp
+const EVENT_SIZE: usize = 2 + 8;
+
+// New events are staged in RAM and written to flash together by `flush`, once the stage
+// is full or when `uptime_task` runs it every minute. Events still staged when power is
+// lost are gone, so the loss window is up to a minute (or EVENT_LOG_LEN events).
+// A larger stage wouldn't help, the stored ring only keeps EVENT_LOG_LEN events anyway.
+static STAGE: Mutex<CriticalSectionRawMutex, Cell<Stage>> = Mutex::new(Cell::new(Stage { pending: Vec::new(), last: None }));
+
+#[derive(Default)]
+struct Stage {
+    pending: Vec<Event, EVENT_LOG_LEN>,
+    // Last event pushed since boot, for debouncing
+    last: Option<Event>,
+}
+
+/// A single logged event.
+/// `ts` is in seconds since boot, there is no RTC on this board yet.
+#[derive(Format, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Event {
+    pub code: u16,
+    pub ts: i64,
+}
+
+/// Fixed-size ring of the most recent events, stored as a single map item
+pub type EventLog = RingBuffer<Event, EVENT_LOG_LEN>;
+
+impl RingItem for Event {
+    const SIZE: usize = EVENT_SIZE;
+
+    fn write_to(&self, buffer: &mut [u8]) {
+        buffer[..2].copy_from_slice(&self.code.to_le_bytes());
+        buffer[2..EVENT_SIZE].copy_from_slice(&self.ts.to_le_bytes());
+    }
+
+    fn read_from(buffer: &[u8]) -> Self {
+        Event {
+            code: u16::from_le_bytes([buffer[0], buffer[1]]),
+            ts: i64::from_le_bytes(buffer[2..EVENT_SIZE].try_into().unwrap()),
+        }
+    }
+}
+
+/// Record an event in the log. It's staged in RAM first, see STAGE.
+/// Identical consecutive events within `DEBOUNCE_SECS` are dropped to save flash writes.
+pub async fn push_event<F: AsyncNorFlash>(storage: &mut StorageManager<F>, code: u16) -> Result<(), ()>
+where
+    F::Error: Format,
+{
+    let event = Event { code, ts: Instant::now().as_secs() as i64 };
+    let full = STAGE.lock(|stage| {
+        let mut staged = stage.take();
+        let full = match staged.last {
+            Some(last) if last.code == code && (0..DEBOUNCE_SECS).contains(&(event.ts - last.ts)) => {
+                info!("Event {} debounced", code);
+                false
+            }
+            _ => {
+                staged.pending.push(event).ok();
+                staged.last = Some(event);
+                staged.pending.is_full()
+            }
+        };
+        stage.set(staged);
+        full
+    });
+    if full {
+        flush(storage).await
+    } else {
+        Ok(())
+    }
+}
+
+/// Write the staged events to flash. On failure they stay staged for the next attempt.
+pub async fn flush<F: AsyncNorFlash>(storage: &mut StorageManager<F>) -> Result<(), ()>
+where
+    F::Error: Format,
+{
+    let log = read_events(storage).await;
+    let pending = STAGE.lock(|stage| {
+        let mut staged = stage.take();
+        let pending = core::mem::take(&mut staged.pending);
+        stage.set(staged);
+        pending
+    });
+    if pending.is_empty() {
+        return Ok(());
+    }
+    let result = storage.set_event_log(&log).await;
+    if result.is_err() {
+        // Nothing new can have been staged meanwhile, the storage manager is borrowed
+        STAGE.lock(|stage| {
+            let mut staged = stage.take();
+            staged.pending = pending;
+            stage.set(staged);
+        });
+    }
+    result
+}
+
+/// Read the log, including events that are still staged in RAM.
+/// An absent or unreadable log in flash is taken as empty.
+pub async fn read_events<F: AsyncNorFlash>(storage: &mut StorageManager<F>) -> EventLog
+where
+    F::Error: Format,
+{
+    let mut log = storage.get_event_log().await.ok().flatten().unwrap_or_default();
+    STAGE.lock(|stage| {
+        let staged = stage.take();
+        for &event in &staged.pending {
+            log.push(event);
+        }
+        stage.set(staged);
+    });
+    log
+}
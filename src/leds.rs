@@ -0,0 +1,66 @@
+use core::cell::Cell;
+use embassy_futures::select::{select, Either};
+use embassy_stm32::gpio::Output;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+
+// Half period of the automatic blinking
+const BLINK_HALF_PERIOD: Duration = Duration::from_millis(500);
+
+pub const LED_COUNT: usize = 2;
+
+/// What an LED does, set at runtime by the `led1`/`led2` commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LedMode {
+    /// Toggled automatically by `led_task`, the default
+    Blink,
+    On,
+    Off,
+}
+
+impl LedMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            LedMode::Blink => "blink",
+            LedMode::On => "on",
+            LedMode::Off => "off",
+        }
+    }
+}
+
+// Mode of each LED, LED1 (PA6) first
+static MODES: Mutex<CriticalSectionRawMutex, Cell<[LedMode; LED_COUNT]>> = Mutex::new(Cell::new([LedMode::Blink; LED_COUNT]));
+// Wakes `led_task` so a new mode shows right away instead of at the next toggle
+static MODES_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Override what LED `index` (0-based) does, `LedMode::Blink` hands it back to automatic toggling
+pub fn set_mode(index: usize, mode: LedMode) {
+    MODES.lock(|modes| {
+        let mut m = modes.get();
+        m[index] = mode;
+        modes.set(m);
+    });
+    MODES_CHANGED.signal(());
+}
+
+/// Drives the status LEDs: blinking ones toggle together, the others follow their mode
+#[embassy_executor::task]
+pub async fn led_task(mut leds: [Output<'static>; LED_COUNT]) {
+    let mut lit = false;
+    loop {
+        let modes = MODES.lock(|modes| modes.get());
+        for (led, mode) in leds.iter_mut().zip(modes) {
+            match mode {
+                LedMode::Blink => led.set_level(lit.into()),
+                LedMode::On => led.set_high(),
+                LedMode::Off => led.set_low(),
+            }
+        }
+        // A mode change is applied right away, the blink phase carries on from where it was
+        if let Either::First(_) = select(Timer::after(BLINK_HALF_PERIOD), MODES_CHANGED.wait()).await {
+            lit = !lit;
+        }
+    }
+}
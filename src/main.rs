@@ -2,12 +2,34 @@
 #![no_main]
 #![feature(impl_trait_in_assoc_type)]
 
+#[cfg(feature = "audit")]
+mod audit;
+#[cfg(feature = "base64")]
+mod base64;
+#[cfg(feature = "bridge")]
+mod bridge;
+mod calibration;
 mod cli;
+mod clocks;
+mod crc;
+mod errstat;
+mod eventlog;
+#[cfg(feature = "leds")]
+mod leds;
+#[cfg(feature = "diagnostics")]
+mod mem;
+#[cfg(feature = "diagnostics")]
+mod regs;
+mod security;
+#[cfg(feature = "sensor")]
+mod sensor;
 mod storage;
+#[cfg(feature = "telemetry")]
+mod telemetry;
 
 use embassy_stm32::flash::Flash;
-use embassy_stm32::rcc::{Hse, HseMode, Pll, PllSource, Sysclk};
-use embassy_stm32::time::Hertz;
+use embassy_stm32::pac::rcc::vals::Uartsel;
+use embassy_stm32::rcc::{Hse, HseMode, LsConfig, Pll, PllSource, Sysclk};
 use panic_probe as _;
 
 use defmt::{info, unwrap};
@@ -15,25 +37,39 @@ use embassy_executor::Spawner;
 use embassy_stm32::usart::{Config, BufferedUart};
 use embassy_stm32::{bind_interrupts, peripherals, usart};
 // use embassy_time::Timer; // Timer is no longer used in the loop
-use heapless::String;
 use rtt_target::rtt_init_defmt;
-use ufmt::uwrite;
 
 use storage::async_flash_wrapper;
 
 bind_interrupts!(struct Irqs {
     LPUART1 => usart::BufferedInterruptHandler<peripherals::LPUART1>;
+    #[cfg(feature = "sensor")]
+    ADC1_COMP => embassy_stm32::adc::InterruptHandler<peripherals::ADC1>;
 });
 
+// Interrupts the firmware has handlers for, reported by the `irqs` command.
+// Add an entry here whenever a feature binds another interrupt above.
+#[cfg(feature = "diagnostics")]
+pub const BOUND_IRQS: &[(&str, embassy_stm32::interrupt::Interrupt)] = &[
+    ("LPUART1", embassy_stm32::interrupt::Interrupt::LPUART1),
+    #[cfg(feature = "sensor")]
+    ("ADC1_COMP", embassy_stm32::interrupt::Interrupt::ADC1_COMP),
+    // Bound by embassy-stm32 for the embassy-time driver, selected with time-driver-tim22 in Cargo.toml
+    ("TIM22", embassy_stm32::interrupt::Interrupt::TIM22),
+];
+
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
+    // Has to run before interrupts are enabled, see mem::paint_stack
+    #[cfg(feature = "diagnostics")]
+    mem::paint_stack();
     // the C booloader disables interrupts, so we need to re-enable them
     unsafe { cortex_m::interrupt::enable() };
     rtt_init_defmt!();
     let mut config = embassy_stm32::Config::default();
     {
         config.rcc.hse = Some(Hse {
-            freq: Hertz::mhz(16),
+            freq: clocks::HSE_FREQ,
             mode: HseMode::Oscillator,
         });
         config.rcc.pll = Some(Pll {
@@ -42,32 +78,35 @@ async fn main(spawner: Spawner) {
             div: embassy_stm32::rcc::PllDiv::DIV2,
         });
         config.rcc.sys = Sysclk::PLL1_R;
+        config.rcc.mux.lpuart1sel = clocks::LPUART_CLOCK;
+        // Only slow baud rates run the LPUART from the LSE, don't wait for the crystal otherwise
+        if clocks::LPUART_CLOCK == Uartsel::LSE {
+            config.rcc.ls = LsConfig::default_lse();
+        }
     }
     let p = embassy_stm32::init(config);
 
     // Initialize flash
+    #[cfg(not(feature = "ram-fallback"))]
     let flash = async_flash_wrapper(Flash::new_blocking(p.FLASH));
+    #[cfg(feature = "ram-fallback")]
+    let flash = async_flash_wrapper(storage::StorageBackend::Flash(Flash::new_blocking(p.FLASH)));
 
     // Create and initialize the storage manager
-    let mut storage_manager = storage::StorageManager::new(flash);
-
-    // --- DEVELOPMENT ONLY: Erase the storage area ONCE ---
-    // Comment this out after the first successful run
-    match storage_manager.erase_map_area().await {
-        Ok(_) => info!("Storage area erased successfully for initial setup."),
-        Err(e) => {
-            defmt::error!("Failed to erase storage area: {}", defmt::Debug2Format(&e));
-            // Decide how to handle this - maybe panic?
-            panic!("Storage erase failed");
-        }
-    }
+    // Fails when the firmware has grown into the storage area, see StorageInitError
+    #[cfg(not(feature = "ram-fallback"))]
+    let storage_manager = unwrap!(storage::StorageManager::new(flash));
+    // Keeps the device running on a RAM store instead, losing the config on reboot
+    #[cfg(feature = "ram-fallback")]
+    let storage_manager = storage::StorageManager::new(flash).unwrap_or_else(storage::StorageManager::ram_fallback);
 
     // Get the static reference to the initialized Mutex
     let storage_manager_mutex = storage::STORAGE_MANAGER.init(
         embassy_sync::mutex::Mutex::new(storage_manager)
     );
 
-    // Initialize and read state from storage
+    // Initialize and read state from storage. Storage that holds no valid map, e.g. on the first
+    // boot after flashing, is erased here. Anything else is kept, stored data has to survive a reset.
     let initial_state = match storage_manager_mutex.lock().await.initialize().await {
         Ok(state) => {
             info!("Loaded state: counter={}, mode={}", state.counter, state.mode);
@@ -79,13 +118,24 @@ async fn main(spawner: Spawner) {
         }
     };
 
+    // Nothing branches on the role yet, it's here for the bus protocols (e.g. Modbus master vs slave)
+    let role = storage_manager_mutex.lock().await.get_role().await.unwrap_or_default();
+    info!("Device role: {}", role);
+
+    if eventlog::push_event(&mut *storage_manager_mutex.lock().await, eventlog::EVT_BOOT).await.is_err() {
+        info!("Failed to log boot event");
+    }
+
+    #[cfg(feature = "sensor")]
+    sensor::init(embassy_stm32::adc::Adc::new(p.ADC1, Irqs)).await;
+
     // Initialize CLI state (in-memory state mutex and update signal)
     // This calls STATE.init() and STATE_UPDATED.init() internally
     cli::init(initial_state);
 
     // Initialize UART for CLI
     let mut uart_config = Config::default();
-    uart_config.baudrate = 57600;
+    uart_config.baudrate = clocks::LPUART_BAUD;
     static mut TX_BUF: [u8; 256] = [0; 256];
     static mut RX_BUF: [u8; 256] = [0; 256];
     // Use unsafe to get mutable references to static buffers
@@ -103,12 +153,30 @@ async fn main(spawner: Spawner) {
     )
     .unwrap();
 
+    // The CLI talks to the UART through the bridge pipes
+    #[cfg(feature = "bridge")]
+    let usart = {
+        static UART_BRIDGE: bridge::Bridge = bridge::Bridge::new();
+        unwrap!(spawner.spawn(bridge::uart_bridge_task(usart, &UART_BRIDGE)));
+        UART_BRIDGE.stream()
+    };
+
+    // Status LEDs on PA6/PA7, blinking until the `led1`/`led2` commands say otherwise
+    #[cfg(feature = "leds")]
+    {
+        use embassy_stm32::gpio::{Level, Output, Speed};
+        let leds = [Output::new(p.PA6, Level::Low, Speed::Low), Output::new(p.PA7, Level::Low, Speed::Low)];
+        unwrap!(spawner.spawn(leds::led_task(leds)));
+    }
+
     // Spawn CLI task, passing the storage manager mutex reference
     unwrap!(spawner.spawn(cli::cli_task(usart, storage_manager_mutex)));
+    unwrap!(spawner.spawn(storage::uptime_task(storage_manager_mutex)));
+    #[cfg(feature = "telemetry")]
+    unwrap!(spawner.spawn(telemetry::telemetry_task(storage_manager_mutex)));
 
     // Main task can do other work in parallel
     // For example, let's periodically react to state changes
-    let mut message: String<256> = String::new();
     let mut cnt = 0;
 
     loop {
@@ -122,10 +190,8 @@ async fn main(spawner: Spawner) {
 
         // Example action based on state
         if state.mode > 0 {
-            message.clear();
-            uwrite!(message, "Main task action: counter={}, mode={}, cnt={}\r\n",
-                state.counter, state.mode, cnt).ok();
-            info!("{}", message.as_str());
+            // Logged directly, formatting into a String first would only cost flash
+            info!("Main task action: counter={}, mode={}, cnt={}", state.counter, state.mode, cnt);
             cnt += 1;
         }
 
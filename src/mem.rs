@@ -0,0 +1,47 @@
+use core::ptr::addr_of;
+use cortex_m::register::msp;
+
+// Pattern the free stack is filled with at boot
+const PAINT: u32 = 0x5AA5_C3E1;
+
+// Bytes right below the stack pointer that `paint_stack` leaves alone, they hold its own frame
+const PAINT_MARGIN: usize = 64;
+
+extern "C" {
+    // Set by cortex-m-rt: the end of .bss/.uninit, the lowest address the stack can grow down to
+    static __sheap: u32;
+    // Set by cortex-m-rt: the initial stack pointer at the top of RAM
+    static _stack_start: u32;
+}
+
+/// Fill the stack below the current stack pointer with a known pattern, so `stack_usage`
+/// can later tell how deep the stack has ever grown.
+/// Call it first thing at boot, before interrupts are enabled, as an interrupt handler
+/// could otherwise have its frame overwritten.
+pub fn paint_stack() {
+    let bottom = addr_of!(__sheap) as usize;
+    let top = msp::read() as usize - PAINT_MARGIN;
+    let mut p = bottom as *mut u32;
+    while (p as usize) < top {
+        // SAFETY: [bottom, top) is RAM between the statics and the live part of the stack
+        unsafe {
+            p.write_volatile(PAINT);
+            p = p.add(1);
+        }
+    }
+}
+
+/// Bytes of stack used and free, as `(used, free)`.
+/// This is a high-water mark since `paint_stack` ran, not the usage right now:
+/// the deepest the stack has ever been, found as the lowest word no longer holding the pattern.
+pub fn stack_usage() -> (usize, usize) {
+    let bottom = addr_of!(__sheap) as usize;
+    let top = addr_of!(_stack_start) as usize;
+    let mut p = bottom as *const u32;
+    // SAFETY: only reads words within RAM, between the statics and the top of the stack
+    while (p as usize) < top && unsafe { p.read_volatile() } == PAINT {
+        p = unsafe { p.add(1) };
+    }
+    let free = p as usize - bottom;
+    (top - bottom - free, free)
+}
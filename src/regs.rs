@@ -0,0 +1,70 @@
+use embassy_stm32::pac;
+use cortex_m::peripheral::NVIC;
+
+/// Register block that can be dumped with the `regs` command
+#[derive(Debug, Clone, Copy)]
+pub enum RegBlock {
+    Rcc,
+    Pwr,
+    // GPIO port, 0 = A
+    Gpio(u8),
+}
+
+impl RegBlock {
+    /// Parse a block name: "rcc", "pwr" or "gpioa" to "gpioc"
+    pub fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "rcc" => RegBlock::Rcc,
+            "pwr" => RegBlock::Pwr,
+            "gpioa" => RegBlock::Gpio(0),
+            "gpiob" => RegBlock::Gpio(1),
+            "gpioc" => RegBlock::Gpio(2),
+            _ => return None,
+        })
+    }
+}
+
+/// Read the raw values of the interesting registers in `block`, calling `f` with each name and value
+pub fn dump(block: RegBlock, mut f: impl FnMut(&'static str, u32)) {
+    match block {
+        RegBlock::Rcc => {
+            let rcc = pac::RCC;
+            f("CR", rcc.cr().read().0);
+            f("ICSCR", rcc.icscr().read().0);
+            f("CFGR", rcc.cfgr().read().0);
+            f("CSR", rcc.csr().read().0);
+            f("CCIPR", rcc.ccipr().read().0);
+            f("IOPENR", rcc.gpioenr().read().0);
+            f("AHBENR", rcc.ahbenr().read().0);
+            f("APB1ENR", rcc.apb1enr().read().0);
+            f("APB2ENR", rcc.apb2enr().read().0);
+        }
+        RegBlock::Pwr => {
+            f("CR", pac::PWR.cr().read().0);
+            f("CSR", pac::PWR.csr().read().0);
+        }
+        RegBlock::Gpio(port) => {
+            let gpio = match port {
+                0 => pac::GPIOA,
+                1 => pac::GPIOB,
+                _ => pac::GPIOC,
+            };
+            f("MODER", gpio.moder().read().0);
+            f("OTYPER", gpio.otyper().read().0);
+            f("OSPEEDR", gpio.ospeedr().read().0);
+            f("PUPDR", gpio.pupdr().read().0);
+            f("IDR", gpio.idr().read().0);
+            f("ODR", gpio.odr().read().0);
+            f("AFRL", gpio.afr(0).read().0);
+            f("AFRH", gpio.afr(1).read().0);
+        }
+    }
+}
+
+/// Report each interrupt in `BOUND_IRQS` with whether it's enabled in the NVIC and its priority.
+/// The L0 implements 2 priority bits, so the priority is 0 (highest) to 3.
+pub fn irqs(mut f: impl FnMut(&'static str, bool, u8)) {
+    for &(name, irq) in crate::BOUND_IRQS {
+        f(name, NVIC::is_enabled(irq), NVIC::get_priority(irq) >> 6);
+    }
+}
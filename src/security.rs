@@ -0,0 +1,34 @@
+use embassy_stm32::pac;
+
+/// Flash readout protection level
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RdpLevel {
+    /// No protection, the debugger can read flash
+    Level0,
+    /// Flash can't be read through the debugger, reverting to level 0 mass-erases it
+    Level1,
+    /// Debug interface permanently disabled, can't be reverted
+    Level2,
+}
+
+impl RdpLevel {
+    pub fn name(self) -> &'static str {
+        match self {
+            RdpLevel::Level0 => "level 0 (unprotected)",
+            RdpLevel::Level1 => "level 1",
+            RdpLevel::Level2 => "level 2",
+        }
+    }
+}
+
+/// Readout protection level currently in effect.
+/// Only reports it, setting RDP is left to the programmer: level 2 can't be undone.
+pub fn rdp_level() -> RdpLevel {
+    // FLASH_OPTR bits 7:0 hold RDPROT as loaded from the option bytes at reset (RM0377, 3.4.1):
+    // 0xAA is level 0, 0xCC is level 2, any other value is level 1
+    match pac::FLASH.optr().read().rdprot() {
+        0xAA => RdpLevel::Level0,
+        0xCC => RdpLevel::Level2,
+        _ => RdpLevel::Level1,
+    }
+}
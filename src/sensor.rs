@@ -0,0 +1,61 @@
+use embassy_stm32::adc::{Adc, SampleTime};
+use embassy_stm32::peripherals::ADC1;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+
+// Calibration channels (see calibration.rs) of the on-chip sensors
+pub const CAL_CH_VDD: u8 = 0;
+pub const CAL_CH_TEMP: u8 = 1;
+
+// Factory calibration values in system memory, measured at VDDA = 3.0 V (RM0377, 14.9)
+const VREFINT_CAL: *const u16 = 0x1FF8_0078 as *const u16;
+const TS_CAL1: *const u16 = 0x1FF8_007A as *const u16;
+const TS_CAL2: *const u16 = 0x1FF8_007E as *const u16;
+const CAL_VDDA_MV: u32 = 3000;
+const TS_CAL1_TEMP: i32 = 30;
+const TS_CAL2_TEMP: i32 = 130;
+
+// The ADC, shared by everything that samples. `None` until `init` has run.
+static ADC: Mutex<CriticalSectionRawMutex, Option<Adc<'static, ADC1>>> = Mutex::new(None);
+
+/// One reading of the internal reference and temperature sensor
+pub struct Sample {
+    pub vref_raw: u16,
+    pub temp_raw: u16,
+    /// Supply voltage in mV, derived from the internal reference
+    pub vdd_mv: i32,
+    /// Die temperature in degrees C
+    pub temp_c: i32,
+}
+
+/// Hand the ADC over for sampling
+pub async fn init(mut adc: Adc<'static, ADC1>) {
+    // The temperature sensor needs a sampling time of at least 10 us
+    adc.set_sample_time(SampleTime::CYCLES160_5);
+    *ADC.lock().await = Some(adc);
+}
+
+/// Take a single reading, `None` if the ADC hasn't been initialized
+pub async fn sample() -> Option<Sample> {
+    let mut adc = ADC.lock().await;
+    let adc = adc.as_mut()?;
+    let mut vref = adc.enable_vref();
+    let mut temp = adc.enable_temperature();
+    let vref_raw = adc.read(&mut vref).await;
+    let temp_raw = adc.read(&mut temp).await;
+
+    // SAFETY: the factory calibration values are always readable
+    let (vref_cal, ts_cal1, ts_cal2) = unsafe { (VREFINT_CAL.read_volatile(), TS_CAL1.read_volatile(), TS_CAL2.read_volatile()) };
+    let vdd_mv = CAL_VDDA_MV * vref_cal as u32 / (vref_raw as u32).max(1);
+    // Scale the reading to what it would be at the calibration voltage, then interpolate
+    let temp_at_cal = (temp_raw as u32 * vdd_mv / CAL_VDDA_MV) as i32;
+    let temp_c = (TS_CAL2_TEMP - TS_CAL1_TEMP) * (temp_at_cal - ts_cal1 as i32) / (ts_cal2 as i32 - ts_cal1 as i32).max(1)
+        + TS_CAL1_TEMP;
+
+    Some(Sample {
+        vref_raw,
+        temp_raw,
+        vdd_mv: vdd_mv as i32,
+        temp_c,
+    })
+}
@@ -1,21 +1,115 @@
 use defmt::{Format, info};
-use embassy_stm32::flash::{Blocking, Flash};
+use embassy_stm32::flash::{Blocking, Flash, FLASH_BASE, MAX_ERASE_SIZE, WRITE_SIZE};
 use sequential_storage::{
     cache::NoCache,
-    map::{fetch_item, store_item},
+    map::{fetch_item, store_item, SerializationError, Value},
     Error as StorageError // Import the error type for the erase function result
 };
 use embassy_embedded_hal::adapter::BlockingAsync;
 use embedded_storage::nor_flash::NorFlash;
+#[cfg(feature = "ram-fallback")]
+use embedded_storage::nor_flash::{ErrorType, ReadNorFlash};
+#[cfg(feature = "ram-fallback")]
+use core::sync::atomic::{AtomicBool, Ordering};
 use embedded_storage_async::nor_flash::NorFlash as AsyncNorFlash;
+use core::mem::size_of;
 use core::ops::Range;
+use core::ptr::addr_of;
 use embassy_sync::mutex::Mutex;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+#[cfg(any(feature = "audit", feature = "write-limit"))]
+use embassy_time::Instant;
+use embassy_time::{Duration, Timer};
+use heapless::{LinearMap, String};
+use sequential_storage::map::fetch_all_items;
 use static_cell::StaticCell;
+use ufmt::{uDisplay, uwrite, uWrite, Formatter};
+
+#[cfg(feature = "audit")]
+use crate::audit::{AuditLog, AuditRecord};
+use crate::calibration::CalTable;
+use crate::errstat::{self, ErrCounter};
+use crate::eventlog::{self, EventLog};
+#[cfg(feature = "telemetry")]
+use crate::telemetry::RecentSamples;
 
 // Define constants for our keys (using u32 which implements Key trait)
 pub const KEY_COUNTER: u32 = 0;
 pub const KEY_MODE: u32 = 1;
+pub const KEY_EVENT_LOG: u32 = 2;
+pub const KEY_TOTAL_HOURS: u32 = 3;
+pub const KEY_CALIBRATION: u32 = 4;
+pub const KEY_FLAGS: u32 = 5;
+pub const KEY_NET_CONFIG: u32 = 6;
+#[cfg(feature = "audit")]
+pub const KEY_AUDIT_LOG: u32 = 7;
+// The CLI prompt ("sys/prompt"), stored as plain ASCII text
+pub const KEY_PROMPT: u32 = 8;
+pub const KEY_ROLE: u32 = 9;
+// Total power-on hours at which the next maintenance is due, and the interval in hours
+#[cfg(feature = "maintenance")]
+pub const KEY_MAINT_DUE: u32 = 10;
+#[cfg(feature = "maintenance")]
+pub const KEY_MAINT_INTERVAL: u32 = 11;
+// Set once a human has finished provisioning the unit ("sys/commissioned"), as a u8 0 or 1.
+// Freshly formatted storage has it missing, which reads as not commissioned.
+pub const KEY_COMMISSIONED: u32 = 12;
+// The last few sensor samples ("telemetry/recent"), a RingBuffer written by `telemetry::record`
+#[cfg(feature = "telemetry")]
+pub const KEY_TELEMETRY: u32 = 13;
+// Seconds between the sensor samples taken by `telemetry::telemetry_task`, as a u32
+#[cfg(feature = "telemetry")]
+pub const KEY_SENSORS_INTERVAL: u32 = 14;
+// Keys `set_num` and `set_bytes` accept, for values the CLI stores without a type of their own.
+// The keys below are the firmware's (KEY_*, including ones a feature that's off would use),
+// the ones above hold the copies of LKG_KEYS and the blobs.
+pub const USER_KEYS: Range<u32> = 0x1000..0x2000_0000;
+#[cfg(feature = "lkg")]
+const _: () = assert!(LKG_KEYS >= USER_KEYS.end);
+// Scratch key for the CLI `bench` command, the last user key so it's far away from any picked by hand
+#[cfg(feature = "diagnostics")]
+pub const KEY_BENCH: u32 = USER_KEYS.end - 1;
+
+// Maintenance interval until one is configured: 90 days of continuous operation
+#[cfg(feature = "maintenance")]
+pub const DEFAULT_MAINT_INTERVAL: u32 = 90 * 24;
+
+// Sensor sampling interval until one is configured, and the longest one `interval` accepts
+#[cfg(feature = "telemetry")]
+pub const DEFAULT_SENSORS_INTERVAL: u32 = 15 * 60;
+#[cfg(feature = "telemetry")]
+pub const MAX_SENSORS_INTERVAL: u32 = 24 * 60 * 60;
+
+// Longest prompt `prompt set` accepts
+pub const PROMPT_MAX_LEN: usize = 16;
+// Prompt used while none is stored. Every prompt is followed by a space.
+pub const DEFAULT_PROMPT: &str = ">";
+
+// Settings `reset_settings` writes back, with their defaults serialized like the setters store them
+const SETTINGS_DEFAULTS: &[(u32, &[u8])] = &[
+    (KEY_FLAGS, &0u32.to_le_bytes()),
+    (KEY_PROMPT, DEFAULT_PROMPT.as_bytes()),
+    (KEY_ROLE, &[DeviceRole::Sensor as u8]),
+    #[cfg(feature = "maintenance")]
+    (KEY_MAINT_INTERVAL, &DEFAULT_MAINT_INTERVAL.to_le_bytes()),
+    #[cfg(feature = "telemetry")]
+    (KEY_SENSORS_INTERVAL, &DEFAULT_SENSORS_INTERVAL.to_le_bytes()),
+];
+
+// Feature flags, bit positions in the bitfield stored under KEY_FLAGS
+pub const FLAG_ECHO: u8 = 0;
+pub const FLAG_LF: u8 = 1;
+
+// Names of the feature flags as used by the CLI
+pub const FLAG_NAMES: [(&str, u8); 2] = [("echo", FLAG_ECHO), ("lf", FLAG_LF)];
+
+// Signaled with the new bitfield whenever a feature flag changes
+pub static FLAGS_CHANGED: Signal<CriticalSectionRawMutex, u32> = Signal::new();
+
+// Signaled with the new interval whenever the sensor sampling interval changes
+#[cfg(feature = "telemetry")]
+pub static SENSORS_INTERVAL_CHANGED: Signal<CriticalSectionRawMutex, u32> = Signal::new();
 
 // Define the App State that will be kept in memory
 #[derive(Format, Clone, Copy, Debug)]
@@ -33,92 +127,1066 @@ impl Default for AppState {
     }
 }
 
+/// What this node does on the bus, the same firmware runs as any of them.
+/// Read at startup, so a change only takes effect after a reboot.
+#[derive(Format, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DeviceRole {
+    #[default]
+    Sensor = 0,
+    Master = 1,
+    Repeater = 2,
+}
+
+// Names of the device roles as used by the CLI
+pub const ROLE_NAMES: [(&str, DeviceRole); 3] = [
+    ("sensor", DeviceRole::Sensor),
+    ("master", DeviceRole::Master),
+    ("repeater", DeviceRole::Repeater),
+];
+
+impl DeviceRole {
+    pub fn name(self) -> &'static str {
+        ROLE_NAMES[self as usize].0
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        ROLE_NAMES.get(value as usize).map(|&(_, role)| role)
+    }
+
+    /// The role called `name` in ROLE_NAMES
+    pub const fn from_name(name: &str) -> Option<Self> {
+        let mut i = 0;
+        while i < ROLE_NAMES.len() {
+            if str_eq(ROLE_NAMES[i].0, name) {
+                return Some(ROLE_NAMES[i].1);
+            }
+            i += 1;
+        }
+        None
+    }
+}
+
+// Every name finds its role, anything else none
+const _: () = {
+    let mut i = 0;
+    while i < ROLE_NAMES.len() {
+        assert!(matches!(DeviceRole::from_name(ROLE_NAMES[i].0), Some(role) if role as usize == i));
+        i += 1;
+    }
+    assert!(DeviceRole::from_name("gateway").is_none());
+    assert!(DeviceRole::from_name("Sensor").is_none());
+    assert!(DeviceRole::from_name("sensors").is_none());
+    assert!(DeviceRole::from_name("").is_none());
+};
+
+/// Byte-wise string equality that also works in const fns
+const fn str_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+impl uDisplay for DeviceRole {
+    fn fmt<W: uWrite + ?Sized>(&self, f: &mut Formatter<'_, W>) -> Result<(), W::Error> {
+        uwrite!(f, "Role: {}\r\n", self.name())
+    }
+}
+
+/// Type and allowed values of a config key, as the `schema` command reports them
+#[cfg(feature = "schema")]
+#[derive(Clone, Copy)]
+pub enum KeyType {
+    /// Unsigned integer of the given width in bits, within `min..=max`
+    Uint { bits: u8, min: u32, max: u32 },
+    /// UTF-8 string of up to the given number of bytes
+    Str(usize),
+    /// `u8` holding one of the names, stored as its position in the list
+    Enum { name: &'static str, values: &'static [&'static str] },
+    /// `u32` bitfield, each name with its bit position
+    Bits(&'static [(&'static str, u8)]),
+    /// `NetConfig`: ip, mask and gateway as dotted IPv4, then the port
+    Net,
+    /// `u8` that is 0 or 1
+    Bool,
+}
+
+/// A typed config key, see SCHEMA
+#[cfg(feature = "schema")]
+pub struct KeySchema {
+    pub key: u32,
+    pub name: &'static str,
+    pub ty: KeyType,
+}
+
+// DeviceRole names in the order of their stored value
+#[cfg(feature = "schema")]
+const ROLE_VALUES: [&str; ROLE_NAMES.len()] = [ROLE_NAMES[0].0, ROLE_NAMES[1].0, ROLE_NAMES[2].0];
+
+/// The config keys host tools may edit. Counters, logs and the calibration table
+/// are written by the firmware itself and left out.
+#[cfg(feature = "schema")]
+pub const SCHEMA: &[KeySchema] = &[
+    KeySchema { key: KEY_MODE, name: "mode", ty: KeyType::Uint { bits: 8, min: 0, max: u8::MAX as u32 } },
+    KeySchema { key: KEY_FLAGS, name: "flags", ty: KeyType::Bits(&FLAG_NAMES) },
+    KeySchema { key: KEY_NET_CONFIG, name: "net", ty: KeyType::Net },
+    KeySchema { key: KEY_PROMPT, name: "prompt", ty: KeyType::Str(PROMPT_MAX_LEN) },
+    KeySchema { key: KEY_ROLE, name: "role", ty: KeyType::Enum { name: "DeviceRole", values: &ROLE_VALUES } },
+    KeySchema { key: KEY_COMMISSIONED, name: "commissioned", ty: KeyType::Bool },
+    #[cfg(feature = "maintenance")]
+    KeySchema { key: KEY_MAINT_INTERVAL, name: "maint_interval", ty: KeyType::Uint { bits: 32, min: 1, max: u32::MAX } },
+    #[cfg(feature = "telemetry")]
+    KeySchema { key: KEY_SENSORS_INTERVAL, name: "sensors_interval", ty: KeyType::Uint { bits: 32, min: 1, max: MAX_SENSORS_INTERVAL } },
+];
+
+// The keys `export_json` writes, in that order, with their JSON names.
+// Every key in SCHEMA is exported under the same name, checked below.
+const EXPORT_KEYS: &[(u32, &str)] = &[
+    (KEY_COUNTER, "counter"),
+    (KEY_MODE, "mode"),
+    (KEY_TOTAL_HOURS, "total_hours"),
+    (KEY_FLAGS, "flags"),
+    (KEY_NET_CONFIG, "net"),
+    (KEY_PROMPT, "prompt"),
+    (KEY_ROLE, "role"),
+    (KEY_COMMISSIONED, "commissioned"),
+    #[cfg(feature = "maintenance")]
+    (KEY_MAINT_INTERVAL, "maint_interval"),
+    #[cfg(feature = "telemetry")]
+    (KEY_SENSORS_INTERVAL, "sensors_interval"),
+];
+
+#[cfg(feature = "schema")]
+const _: () = {
+    let mut i = 0;
+    while i < SCHEMA.len() {
+        let mut j = 0;
+        while j < EXPORT_KEYS.len() && EXPORT_KEYS[j].0 != SCHEMA[i].key {
+            j += 1;
+        }
+        assert!(j < EXPORT_KEYS.len() && str_eq(EXPORT_KEYS[j].1, SCHEMA[i].name), "SCHEMA key missing from EXPORT_KEYS");
+        i += 1;
+    }
+};
+
+// Every setting is exported, so with their defaults the export is never empty
+const _: () = {
+    let mut i = 0;
+    while i < SETTINGS_DEFAULTS.len() {
+        let mut j = 0;
+        while j < EXPORT_KEYS.len() && EXPORT_KEYS[j].0 != SETTINGS_DEFAULTS[i].0 {
+            j += 1;
+        }
+        assert!(j < EXPORT_KEYS.len(), "setting missing from EXPORT_KEYS");
+        i += 1;
+    }
+};
+
+// How `write_json_str` escapes a byte: the character following the backslash, 'u' for the
+// other control characters and anything outside ASCII, which get four hex digits
+const fn json_escape(b: u8) -> Option<u8> {
+    match b {
+        b'"' | b'\\' => Some(b),
+        b'\n' => Some(b'n'),
+        b'\r' => Some(b'r'),
+        b'\t' => Some(b't'),
+        0..0x20 | 0x7F.. => Some(b'u'),
+        _ => None,
+    }
+}
+
+const _: () = assert!(matches!(json_escape(b'"'), Some(b'"')) && matches!(json_escape(b'\\'), Some(b'\\')));
+const _: () = assert!(matches!(json_escape(b'\n'), Some(b'n')) && matches!(json_escape(0x01), Some(b'u')));
+const _: () = assert!(json_escape(b'a').is_none() && json_escape(b'/').is_none() && json_escape(b'~').is_none());
+
+/// Write `s` as a JSON string, quotes included. Meant for ASCII like the CLI accepts,
+/// other bytes are escaped one by one as if they were Latin-1.
+fn write_json_str<W: uWrite + ?Sized>(w: &mut W, s: &[u8]) -> Result<(), W::Error> {
+    w.write_char('"')?;
+    for &b in s {
+        match json_escape(b) {
+            Some(b'u') => {
+                let hex = b"0123456789abcdef";
+                w.write_str("\\u00")?;
+                w.write_char(hex[(b >> 4) as usize] as char)?;
+                w.write_char(hex[(b & 0xF) as usize] as char)?
+            }
+            Some(escaped) => {
+                w.write_char('\\')?;
+                w.write_char(escaped as char)?
+            }
+            None => w.write_char(b as char)?,
+        }
+    }
+    w.write_char('"')
+}
+
+// Quoted name of the next field of a JSON object, after the opening brace for the first
+// field and a comma for the others. The caller writes the closing brace.
+fn write_json_name<W: uWrite + ?Sized>(w: &mut W, first: &mut bool, name: &str) -> Result<(), W::Error> {
+    w.write_char(if core::mem::replace(first, false) { '{' } else { ',' })?;
+    w.write_char('"')?;
+    w.write_str(name)?;
+    w.write_str("\":")
+}
+
+// Whether the stored `bytes` of `key`, one of VALUE_SIZES, decode as the key's type
+#[cfg(feature = "diagnostics")]
+fn decodes(key: u32, bytes: &[u8]) -> bool {
+    match key {
+        KEY_EVENT_LOG => EventLog::deserialize_from(bytes).is_ok(),
+        KEY_CALIBRATION => CalTable::deserialize_from(bytes).is_ok(),
+        KEY_NET_CONFIG => NetConfig::deserialize_from(bytes).is_ok(),
+        #[cfg(feature = "audit")]
+        KEY_AUDIT_LOG => AuditLog::deserialize_from(bytes).is_ok(),
+        #[cfg(feature = "telemetry")]
+        KEY_TELEMETRY => RecentSamples::deserialize_from(bytes).is_ok(),
+        KEY_PROMPT => bytes.len() <= PROMPT_MAX_LEN && core::str::from_utf8(bytes).is_ok(),
+        KEY_ROLE => matches!(*bytes, [role] if DeviceRole::from_u8(role).is_some()),
+        KEY_COMMISSIONED => matches!(bytes, [0 | 1]),
+        // Plain numbers, only their size can be wrong
+        _ => VALUE_SIZES.iter().any(|&(k, size)| k == key && size == bytes.len()),
+    }
+}
+
+// A value of `export_json` from its stored bytes, decoded by the type of `key`.
+// Bytes that don't decode are written as null. Not inlined, it's smaller on its own.
+#[inline(never)]
+fn write_json_value<W: uWrite + ?Sized>(w: &mut W, key: u32, bytes: &[u8]) -> Result<(), W::Error> {
+    // The mode, role and commissioned flag are stored as a u8, the flags and counters as u32s
+    let number = match bytes.len() {
+        1..=4 => Some(bytes.iter().rev().fold(0u32, |n, &b| n << 8 | b as u32)),
+        _ => None,
+    };
+    match (key, number) {
+        (KEY_FLAGS, Some(flags)) => {
+            let mut first = true;
+            for (flag, bit) in FLAG_NAMES {
+                write_json_name(w, &mut first, flag)?;
+                w.write_str(if flags & (1 << bit) != 0 { "true" } else { "false" })?;
+            }
+            w.write_char('}')
+        }
+        (KEY_NET_CONFIG, _) => {
+            let Ok(net) = NetConfig::deserialize_from(bytes) else { return w.write_str("null") };
+            let mut first = true;
+            for (field, addr) in [("ip", net.ip), ("mask", net.mask), ("gw", net.gw)] {
+                write_json_name(w, &mut first, field)?;
+                for (i, octet) in addr.into_iter().enumerate() {
+                    w.write_char(if i == 0 { '"' } else { '.' })?;
+                    uwrite!(w, "{}", octet)?;
+                }
+                w.write_char('"')?;
+            }
+            uwrite!(w, ",\"port\":{}}}", net.port)
+        }
+        (KEY_PROMPT, _) => write_json_str(w, bytes),
+        (KEY_ROLE, Some(role)) => match u8::try_from(role).ok().and_then(DeviceRole::from_u8) {
+            Some(role) => write_json_str(w, role.name().as_bytes()),
+            None => w.write_str("null"),
+        },
+        (KEY_COMMISSIONED, Some(commissioned)) => w.write_str(if commissioned == 1 { "true" } else { "false" }),
+        (_, None) => w.write_str("null"),
+        (_, Some(value)) => uwrite!(w, "{}", value),
+    }
+}
+
+/// One line per key: `key;name;type;values`. Values are `min..max` for integers and bools,
+/// comma-separated names for enums, `name=bit` pairs for bitfields and empty otherwise.
+#[cfg(feature = "schema")]
+impl uDisplay for KeySchema {
+    fn fmt<W: uWrite + ?Sized>(&self, f: &mut Formatter<'_, W>) -> Result<(), W::Error> {
+        uwrite!(f, "{};{};", self.key, self.name)?;
+        match self.ty {
+            KeyType::Uint { bits, min, max } => uwrite!(f, "u{};{}..{}", bits, min, max),
+            KeyType::Str(max_len) => uwrite!(f, "str[{}];", max_len),
+            KeyType::Enum { name, values } => {
+                uwrite!(f, "enum {};", name)?;
+                for (i, value) in values.iter().enumerate() {
+                    uwrite!(f, "{}{}", if i == 0 { "" } else { "," }, *value)?;
+                }
+                Ok(())
+            }
+            KeyType::Bits(names) => {
+                uwrite!(f, "bits u32;")?;
+                for (i, (name, bit)) in names.iter().enumerate() {
+                    uwrite!(f, "{}{}={}", if i == 0 { "" } else { "," }, *name, *bit)?;
+                }
+                Ok(())
+            }
+            KeyType::Net => uwrite!(f, "NetConfig;ip,mask,gw,port"),
+            KeyType::Bool => uwrite!(f, "bool;0..1"),
+        }
+    }
+}
+
+/// Static network settings, for board variants with a network interface
+#[derive(Format, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NetConfig {
+    pub ip: [u8; 4],
+    pub mask: [u8; 4],
+    pub gw: [u8; 4],
+    pub port: u16,
+}
+
+impl NetConfig {
+    /// Size of the config once serialized: three addresses and the port
+    pub const SERIALIZED_SIZE: usize = 3 * 4 + 2;
+}
+
+impl<'a> Value<'a> for NetConfig {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        if buffer.len() < Self::SERIALIZED_SIZE {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[0..4].copy_from_slice(&self.ip);
+        buffer[4..8].copy_from_slice(&self.mask);
+        buffer[8..12].copy_from_slice(&self.gw);
+        buffer[12..14].copy_from_slice(&self.port.to_le_bytes());
+        Ok(Self::SERIALIZED_SIZE)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<Self, SerializationError> {
+        if buffer.len() < Self::SERIALIZED_SIZE {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        Ok(NetConfig {
+            ip: buffer[0..4].try_into().unwrap(),
+            mask: buffer[4..8].try_into().unwrap(),
+            gw: buffer[8..12].try_into().unwrap(),
+            port: u16::from_le_bytes([buffer[12], buffer[13]]),
+        })
+    }
+}
+
+// One line per field, as shown by the CLI `net` and `show` commands
+impl uDisplay for NetConfig {
+    fn fmt<W: uWrite + ?Sized>(&self, f: &mut Formatter<'_, W>) -> Result<(), W::Error> {
+        for (name, [a, b, c, d]) in [("IP", self.ip), ("Mask", self.mask), ("Gateway", self.gw)] {
+            uwrite!(f, "{}: {}.{}.{}.{}\r\n", name, a, b, c, d)?;
+        }
+        uwrite!(f, "Port: {}\r\n", self.port)
+    }
+}
+
+/// A fixed-size element of a `RingBuffer`
+pub trait RingItem: Copy + Default {
+    /// Serialized size, the same for every value
+    const SIZE: usize;
+    /// Write the value into the first SIZE bytes of `buffer`
+    fn write_to(&self, buffer: &mut [u8]);
+    /// Read a value back from the first SIZE bytes of `buffer`
+    fn read_from(buffer: &[u8]) -> Self;
+}
+
+/// The last `N` (at most 255) values pushed, stored as a single map item.
+/// Storing rewrites the whole ring, so keep `N` small and push to a copy in RAM
+/// that's only stored once in a while, like `telemetry::record` does.
+#[derive(Clone, Copy)]
+pub struct RingBuffer<T: RingItem, const N: usize> {
+    items: [T; N],
+    // Index of the slot the next value will be written to
+    head: u8,
+    // Number of valid values, saturates at N
+    len: u8,
+}
+
+impl<T: RingItem, const N: usize> RingBuffer<T, N> {
+    /// Size of the ring once serialized: head and len bytes followed by all slots
+    pub const SERIALIZED_SIZE: usize = 2 + N * T::SIZE;
+
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Append a value, overwriting the oldest one once the ring is full
+    pub const fn push(&mut self, value: T) {
+        self.items[self.head as usize] = value;
+        self.head = ((self.head as usize + 1) % N) as u8;
+        if (self.len as usize) < N {
+            self.len += 1;
+        }
+    }
+
+    // Slot of the `i`th oldest value
+    const fn slot(&self, i: usize) -> usize {
+        (self.head as usize + N - self.len as usize + i) % N
+    }
+
+    /// The value pushed last, `None` while the ring is empty
+    #[cfg(feature = "telemetry")]
+    pub fn latest(&self) -> Option<T> {
+        (self.len > 0).then(|| self.items[self.slot(self.len as usize - 1)])
+    }
+
+    /// Iterate over the values, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        (0..self.len as usize).map(move |i| self.items[self.slot(i)])
+    }
+}
+
+impl RingItem for u8 {
+    const SIZE: usize = 1;
+
+    fn write_to(&self, buffer: &mut [u8]) {
+        buffer[0] = *self;
+    }
+
+    fn read_from(buffer: &[u8]) -> Self {
+        buffer[0]
+    }
+}
+
+// Wraparound, checked at build time as there's no test target for this firmware:
+// 6 values pushed into 4 slots leave the last 4, oldest first
+const _: () = {
+    let mut ring = RingBuffer::<u8, 4> { items: [0; 4], head: 0, len: 0 };
+    let mut value = 1;
+    while value <= 6 {
+        ring.push(value);
+        value += 1;
+    }
+    assert!(ring.len == 4 && ring.head == 2);
+    assert!(ring.items[ring.slot(0)] == 3 && ring.items[ring.slot(1)] == 4);
+    assert!(ring.items[ring.slot(2)] == 5 && ring.items[ring.slot(3)] == 6);
+};
+
+impl<T: RingItem, const N: usize> Default for RingBuffer<T, N> {
+    fn default() -> Self {
+        Self { items: [T::default(); N], head: 0, len: 0 }
+    }
+}
+
+impl<'a, T: RingItem, const N: usize> Value<'a> for RingBuffer<T, N> {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        let size = Self::SERIALIZED_SIZE;
+        if buffer.len() < size {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[0] = self.head;
+        buffer[1] = self.len;
+        for (item, chunk) in self.items.iter().zip(buffer[2..size].chunks_exact_mut(T::SIZE)) {
+            item.write_to(chunk);
+        }
+        Ok(size)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<Self, SerializationError> {
+        if buffer.len() < Self::SERIALIZED_SIZE {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        let mut ring = Self { head: buffer[0], len: buffer[1], ..Default::default() };
+        if ring.head as usize >= N || ring.len as usize > N {
+            return Err(SerializationError::InvalidFormat);
+        }
+        for (item, chunk) in ring.items.iter_mut().zip(buffer[2..].chunks_exact(T::SIZE)) {
+            *item = T::read_from(chunk);
+        }
+        Ok(ring)
+    }
+}
+
+/// Width and signedness of a number stored with `set_num`/`get_num`
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NumType {
+    U8,
+    U16,
+    U32,
+    I8,
+    I16,
+    I32,
+    F32,
+}
+
+/// A number of any supported type, stored little-endian in exactly its own width
+#[derive(Format, Debug, Clone, Copy, PartialEq)]
+pub enum NumValue {
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    I8(i8),
+    I16(i16),
+    I32(i32),
+    F32(f32),
+}
+
+impl NumValue {
+    // Little-endian bytes in a 4-byte buffer, plus how many of them are used
+    fn to_bytes(self) -> ([u8; 4], usize) {
+        let mut buf = [0u8; 4];
+        let len = match self {
+            NumValue::U8(v) => { buf[..1].copy_from_slice(&v.to_le_bytes()); 1 }
+            NumValue::U16(v) => { buf[..2].copy_from_slice(&v.to_le_bytes()); 2 }
+            NumValue::U32(v) => { buf.copy_from_slice(&v.to_le_bytes()); 4 }
+            NumValue::I8(v) => { buf[..1].copy_from_slice(&v.to_le_bytes()); 1 }
+            NumValue::I16(v) => { buf[..2].copy_from_slice(&v.to_le_bytes()); 2 }
+            NumValue::I32(v) => { buf.copy_from_slice(&v.to_le_bytes()); 4 }
+            NumValue::F32(v) => { buf.copy_from_slice(&v.to_le_bytes()); 4 }
+        };
+        (buf, len)
+    }
+
+    // Decode `bytes` as `ty`, the length must match the type's width exactly
+    fn from_bytes(ty: NumType, bytes: &[u8]) -> Option<Self> {
+        Some(match ty {
+            NumType::U8 => NumValue::U8(u8::from_le_bytes(bytes.try_into().ok()?)),
+            NumType::U16 => NumValue::U16(u16::from_le_bytes(bytes.try_into().ok()?)),
+            NumType::U32 => NumValue::U32(u32::from_le_bytes(bytes.try_into().ok()?)),
+            NumType::I8 => NumValue::I8(i8::from_le_bytes(bytes.try_into().ok()?)),
+            NumType::I16 => NumValue::I16(i16::from_le_bytes(bytes.try_into().ok()?)),
+            NumType::I32 => NumValue::I32(i32::from_le_bytes(bytes.try_into().ok()?)),
+            NumType::F32 => NumValue::F32(f32::from_le_bytes(bytes.try_into().ok()?)),
+        })
+    }
+}
+
 // --- Flash Range Configuration ---
 // Define the flash range RELATIVE TO FLASH BASE (0x08000000)
-// Use the last 1 KiB (1024 bytes = 8 pages) of a 64KiB flash as an example.
+// Use the last MAP_PAGES pages of a 64KiB flash: 512 bytes (4 pages) unless optional features
+// store more, see the storage budget below.
 // STM32L071 Page Size = 128 bytes (0x80)
-// Relative Start Offset: 0x10000 (64k) - 0x400 (1k) = 0xFC00
+// Relative Start Offset: 0x10000 (64k) - 0x200 (512) = 0xFE00 with 4 pages
 // Relative End Offset:   0x10000 (64k)
-// Ensure these are page-aligned (0xFC00 % 0x80 == 0, 0x10000 % 0x80 == 0)
+// Page-aligned as long as the range is made of whole pages
 //
 
-const MAP_FLASH_RANGE: Range<u32> = 0xFE00..0x10000; // Example for 64KiB Flash
+// The range of the store made with `StorageManager::new`, others can be made with `with_range`
+const MAP_FLASH_RANGE: Range<u32> = 0x10000 - (MAP_PAGES * MAX_ERASE_SIZE) as u32..0x10000; // Example for 64KiB Flash
+
+extern "C" {
+    // Set by cortex-m-rt: the end of the firmware image in flash, `.data` initializers included.
+    // It follows the last section link.x puts in FLASH, so a custom linker script has to define it too.
+    static __veneer_limit: u8;
+}
+
+// Size of the storage area in bytes
+#[cfg(any(feature = "diagnostics", feature = "ram-fallback"))]
+pub const MAP_FLASH_SIZE: usize = (MAP_FLASH_RANGE.end - MAP_FLASH_RANGE.start) as usize;
 // --- End Flash Range Configuration ---
 
-// Number of flash pages in our range (optional update based on range size)
-// const PAGE_COUNT: usize = 8; // 1024 bytes / 128 bytes/page = 8 pages
+
+// --- Data Buffer Sizing ---
+// sequential-storage serializes the key followed by the value into `data_buffer`
+// (and reads it back the same way), padded to the flash word size.
+// This used to be a hardcoded 64 bytes, a guess that happened to be large enough.
+// Now it's derived from the largest value we actually store.
+// When adding a new stored type, register its serialized size in `VALUE_SIZES`,
+// the page size check below then catches a type that's too large at build time,
+// instead of the first write of that key failing in the field.
+const VALUE_SIZES: &[(u32, usize)] = &[
+    (KEY_COUNTER, size_of::<u32>()),
+    (KEY_MODE, size_of::<u8>()),
+    (KEY_EVENT_LOG, EventLog::SERIALIZED_SIZE),
+    (KEY_TOTAL_HOURS, size_of::<u32>()),
+    (KEY_CALIBRATION, CalTable::SERIALIZED_SIZE),
+    (KEY_FLAGS, size_of::<u32>()),
+    (KEY_NET_CONFIG, NetConfig::SERIALIZED_SIZE),
+    #[cfg(feature = "audit")]
+    (KEY_AUDIT_LOG, AuditLog::SERIALIZED_SIZE),
+    (KEY_PROMPT, PROMPT_MAX_LEN),
+    (KEY_ROLE, size_of::<u8>()),
+    (KEY_COMMISSIONED, size_of::<u8>()),
+    #[cfg(feature = "telemetry")]
+    (KEY_TELEMETRY, RecentSamples::SERIALIZED_SIZE),
+    #[cfg(feature = "telemetry")]
+    (KEY_SENSORS_INTERVAL, size_of::<u32>()),
+    #[cfg(feature = "maintenance")]
+    (KEY_MAINT_DUE, size_of::<u32>()),
+    #[cfg(feature = "maintenance")]
+    (KEY_MAINT_INTERVAL, size_of::<u32>()),
+    // Stands in for all chunk keys, the headers are smaller
+    #[cfg(feature = "blob")]
+    (BLOB_CHUNK_KEYS, BLOB_CHUNK_SIZE),
+];
+// `num` values and `bytes` blobs from the CLI go under any key and aren't in the list,
+// they're rejected with `BufferTooSmall` when they don't fit.
+const MAX_VALUE_SIZE: usize = max_value_size(VALUE_SIZES);
+// Keys are u32s, serialized ahead of the value
+const KEY_SIZE: usize = size_of::<u32>();
+pub const DATA_BUFFER_SIZE: usize = (KEY_SIZE + MAX_VALUE_SIZE).next_multiple_of(WRITE_SIZE);
+
+// Every registered value has to fit in the buffer along with its key, padded the way
+// sequential-storage reads it back. The item header isn't read into the buffer,
+// the page check below counts it.
+const _: () = {
+    let mut i = 0;
+    while i < VALUE_SIZES.len() {
+        assert!(
+            (KEY_SIZE + VALUE_SIZES[i].1).next_multiple_of(WRITE_SIZE) <= DATA_BUFFER_SIZE,
+            "a value in VALUE_SIZES doesn't fit in DATA_BUFFER_SIZE"
+        );
+        i += 1;
+    }
+};
+
+// On top of the data, every item in flash has a header (`item_overhead_size`),
+// and a flash page loses one word at each end to the page state markers.
+// An item that doesn't fit in a single page can never be stored.
+const _: () = assert!(
+    sequential_storage::item_overhead_size::<AsyncFlash>() as usize + DATA_BUFFER_SIZE
+        <= MAX_ERASE_SIZE - 2 * WRITE_SIZE,
+    "DATA_BUFFER_SIZE is too large for a single flash page"
+);
+
+// How often a write that failed in the flash driver is retried, and the delay in between.
+// Corrupted data, full storage and buffer size errors won't go away by retrying and fail right away.
+const FLASH_WRITE_RETRIES: u32 = 2;
+const FLASH_RETRY_DELAY: Duration = Duration::from_millis(5);
+
+// Blobs too large for DATA_BUFFER_SIZE are written in chunks of up to BLOB_CHUNK_SIZE bytes,
+// each stored as its own item under a chunk key: BLOB_CHUNK_KEYS | bank << 28 | blob key << 12 | index.
+// A header under BLOB_HEADER_KEYS | blob key is written last. Each upload goes to the bank the current
+// version doesn't use, so replacing a blob is atomic: until the header is written, reads see the old one.
+#[cfg(feature = "blob")]
+pub const BLOB_CHUNK_SIZE: usize = 32;
+#[cfg(feature = "blob")]
+const BLOB_CHUNK_KEYS: u32 = 0x4000_0000;
+#[cfg(feature = "blob")]
+const BLOB_HEADER_KEYS: u32 = 0x3000_0000;
+// Flash taken by a full chunk: item header, key and data padded to the flash word size
+#[cfg(feature = "blob")]
+const BLOB_CHUNK_FLASH: usize = sequential_storage::item_overhead_size::<AsyncFlash>() as usize
+    + (KEY_SIZE + BLOB_CHUNK_SIZE).next_multiple_of(WRITE_SIZE);
+// A blob gets at most one flash page of chunks, so BLOB_MAX_LEN is 64 bytes: only twice a value
+// in DATA_BUFFER_SIZE. The chunks of the bank an upload didn't use stay stored until the upload
+// after it overwrites them (nothing can be removed from the L0 flash), so a blob key in use holds
+// on to up to two pages for good. The storage budget counts one blob key, the one `blobtest` uses,
+// any other needs MAP_PAGES raised by hand.
+#[cfg(feature = "blob")]
+const BLOB_MAX_CHUNKS: u16 = ((MAX_ERASE_SIZE - 2 * WRITE_SIZE) / BLOB_CHUNK_FLASH) as u16;
+/// Largest blob `write_chunk` accepts, when written in full chunks
+#[cfg(feature = "blob")]
+pub const BLOB_MAX_LEN: usize = BLOB_MAX_CHUNKS as usize * BLOB_CHUNK_SIZE;
+// The chunk index has 12 bits in the chunk key, and blobs have to hold more than a single value
+#[cfg(feature = "blob")]
+const _: () = assert!(BLOB_MAX_CHUNKS <= 1 << 12 && BLOB_MAX_LEN > DATA_BUFFER_SIZE - KEY_SIZE);
+
+// Last known good config: `mark_good` copies the keys in LKG_CONFIG to LKG_KEYS | key,
+// `restore_good` copies them back. The copies are the same size as the keys themselves.
+#[cfg(feature = "lkg")]
+const LKG_KEYS: u32 = 0x2000_0000;
+// The settings a bad config push can break. Counters, logs, calibration and the
+// commissioning state aren't config and are left alone.
+#[cfg(feature = "lkg")]
+const LKG_CONFIG: &[u32] = &[
+    KEY_MODE,
+    KEY_FLAGS,
+    KEY_NET_CONFIG,
+    KEY_PROMPT,
+    KEY_ROLE,
+    #[cfg(feature = "maintenance")]
+    KEY_MAINT_INTERVAL,
+    #[cfg(feature = "telemetry")]
+    KEY_SENSORS_INTERVAL,
+];
+// Uptime after which a boot counts as healthy and its config is marked good, see `uptime_task`
+#[cfg(feature = "lkg")]
+const LKG_MARK_AFTER_MINUTES: u32 = 1;
+
+// --- Storage Budget ---
+// Flash taken by an item with a value of `size` bytes: header, key and value padded to the flash word size
+const fn item_flash_size(size: usize) -> usize {
+    sequential_storage::item_overhead_size::<AsyncFlash>() as usize + (KEY_SIZE + size).next_multiple_of(WRITE_SIZE)
+}
+
+// The size of `key`'s value in VALUE_SIZES
+#[cfg(feature = "lkg")]
+const fn value_size(key: u32) -> usize {
+    let mut i = 0;
+    while i < VALUE_SIZES.len() {
+        if VALUE_SIZES[i].0 == key {
+            return VALUE_SIZES[i].1;
+        }
+        i += 1;
+    }
+    panic!("key missing from VALUE_SIZES")
+}
+
+// Flash the firmware's own keys can hold at once: every key in VALUE_SIZES,
+// the last known good copies, the `blobtest` blob and the `bench` scratch value.
+const LIVE_DATA_SIZE: usize = {
+    let mut total = 0;
+    let mut i = 0;
+    while i < VALUE_SIZES.len() {
+        total += item_flash_size(VALUE_SIZES[i].1);
+        i += 1;
+    }
+    #[cfg(feature = "lkg")]
+    {
+        let mut i = 0;
+        while i < LKG_CONFIG.len() {
+            total += item_flash_size(value_size(LKG_CONFIG[i]));
+            i += 1;
+        }
+    }
+    // VALUE_SIZES counts one chunk. The `blobtest` blob keeps both banks of chunks
+    // stored, each up to a page, and its header.
+    #[cfg(feature = "blob")]
+    {
+        total += (2 * BLOB_MAX_CHUNKS as usize - 1) * BLOB_CHUNK_FLASH + item_flash_size(size_of::<u32>());
+    }
+    #[cfg(feature = "diagnostics")]
+    {
+        total += item_flash_size(size_of::<u32>());
+    }
+    total
+};
+// Room on top of that for `num` and `bytes` values from the CLI, and for the end of a page
+// an item didn't fit in: items don't span pages.
+const SPARE_DATA_SIZE: usize = 64;
+// What a page holds, its first and last word are the page state markers
+const PAGE_DATA_SIZE: usize = MAX_ERASE_SIZE - 2 * WRITE_SIZE;
+// Pages of the store: enough for the live data and the spare room, plus the page sequential-storage
+// keeps empty for garbage collection. At least 4, features that store more grow the store downwards,
+// which leaves less flash for the firmware. That moves the start of the store, run `erase` after
+// flashing a build with a different number of pages.
+const MAP_PAGES: usize = {
+    let pages = (LIVE_DATA_SIZE + SPARE_DATA_SIZE).div_ceil(PAGE_DATA_SIZE) + 1;
+    if pages < 4 { 4 } else { pages }
+};
+// The budget the store was sized for, so that a fixed MAP_PAGES can't silently overbook it
+// and fail at runtime with `FullStorage`
+const _: () = assert!(
+    LIVE_DATA_SIZE + SPARE_DATA_SIZE <= (MAP_PAGES - 1) * PAGE_DATA_SIZE,
+    "the keys in VALUE_SIZES and their copies don't fit in MAP_PAGES"
+);
+// --- End Storage Budget ---
+
+// Write rate limiter: at most WRITE_LIMIT writes are accepted per WRITE_LIMIT_WINDOW,
+// anything beyond that fails with `WriteError::RateLimited` until the next window starts.
+// A safety valve against a buggy task wearing out the flash.
+#[cfg(feature = "write-limit")]
+const WRITE_LIMIT: u32 = 30;
+#[cfg(feature = "write-limit")]
+const WRITE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+// Most distinct keys `entry_sizes` can report: as many items with a one-byte value as fit in
+// every page of the default store, so it never runs out there. A larger store made with
+// `with_range` can hold more.
+const MAX_LIVE_KEYS: usize = MAP_PAGES * PAGE_DATA_SIZE / item_flash_size(1);
+
+// Most distinct keys whose writes are counted individually
+#[cfg(feature = "diagnostics")]
+const MAX_COUNTED_KEYS: usize = 16;
+
+const fn max_value_size(sizes: &[(u32, usize)]) -> usize {
+    let mut max = 0;
+    let mut i = 0;
+    while i < sizes.len() {
+        if sizes[i].1 > max {
+            max = sizes[i].1;
+        }
+        i += 1;
+    }
+    max
+}
+// --- End Data Buffer Sizing ---
 
 pub fn async_flash_wrapper<F: NorFlash>(flash: F) -> BlockingAsync<F> {
     embassy_embedded_hal::adapter::BlockingAsync::new(flash)
 }
 
+/// Why the storage can't be set up
+#[derive(Debug)]
+pub enum StorageInitError {
+    /// The storage range starts below the end of the firmware, so writing it would corrupt the code.
+    /// Both are absolute addresses.
+    OverlapsFirmware { storage_start: u32, firmware_end: u32 },
+}
+
+// Says what to fix, this ends up in the panic message at boot
+impl Format for StorageInitError {
+    fn format(&self, f: defmt::Formatter) {
+        match self {
+            StorageInitError::OverlapsFirmware { storage_start, firmware_end } => defmt::write!(
+                f,
+                "storage at {=u32:#x} overlaps the firmware ending at {=u32:#x}, move MAP_FLASH_RANGE up or shrink the firmware",
+                storage_start,
+                firmware_end
+            ),
+        }
+    }
+}
+
+/// Errors from writing a value
+#[derive(Debug, Format)]
+pub enum WriteError<E> {
+    /// Error from sequential-storage or the flash driver
+    Storage(StorageError<E>),
+    /// Refused by the write rate limiter, see WRITE_LIMIT
+    #[cfg(feature = "write-limit")]
+    RateLimited,
+    /// `set_num` or `set_bytes` with a key outside USER_KEYS
+    ReservedKey,
+}
+
+impl<E> From<StorageError<E>> for WriteError<E> {
+    fn from(e: StorageError<E>) -> Self {
+        WriteError::Storage(e)
+    }
+}
+
+// The header stored under a blob's header key is a u32: its length, with the top bit telling
+// which bank holds the chunks. Reads take chunks in order until they add up to the length.
+#[cfg(feature = "blob")]
+const BLOB_HEADER_BANK: u32 = 1 << 31;
+
+#[cfg(feature = "blob")]
+const fn blob_header_key(key: u16) -> u32 {
+    BLOB_HEADER_KEYS | key as u32
+}
+
+#[cfg(feature = "blob")]
+const fn blob_chunk_key(key: u16, bank: u8, index: u16) -> u32 {
+    BLOB_CHUNK_KEYS | (bank as u32) << 28 | (key as u32) << 12 | index as u32
+}
+
+/// A blob upload in progress, from `begin_blob` until `commit_blob`
+#[cfg(feature = "blob")]
+pub struct BlobWriter {
+    key: u16,
+    bank: u8,
+    len: u32,
+    chunks: u16,
+}
+
 // Storage manager that encapsulates all flash operations
 pub struct StorageManager<F: AsyncNorFlash> {
     flash: F,
-    // Increased buffer size to prevent potential 'Size' errors.
-    data_buffer: [u8; 64],
+    // Flash range of this store, relative to the flash base. Each range is a store of its own,
+    // the same key in two of them holds two independent values.
+    range: Range<u32>,
+    // Sized for the largest key + value we store, see DATA_BUFFER_SIZE
+    data_buffer: [u8; DATA_BUFFER_SIZE],
+    // Writes per key since boot, for spotting keys that wear the flash
+    #[cfg(feature = "diagnostics")]
+    write_counts: LinearMap<u32, u32, MAX_COUNTED_KEYS>,
+    // Writes to keys that didn't fit in `write_counts`
+    #[cfg(feature = "diagnostics")]
+    other_writes: u32,
+    // Start of the current rate limiter window and the writes accepted in it
+    #[cfg(feature = "write-limit")]
+    window_start: Instant,
+    #[cfg(feature = "write-limit")]
+    window_writes: u32,
+    // A key in LKG_CONFIG was written since boot, the config in effect hasn't been through a boot yet
+    #[cfg(feature = "lkg")]
+    config_changed: bool,
 }
 
 // Define concrete type aliases for STORAGE_MANAGER
 type ConcreteFlash = Flash<'static, Blocking>;
+#[cfg(not(feature = "ram-fallback"))]
 pub type AsyncFlash = BlockingAsync<ConcreteFlash>;
+#[cfg(feature = "ram-fallback")]
+pub type AsyncFlash = BlockingAsync<StorageBackend>;
 pub type ConcreteStorageManager = StorageManager<AsyncFlash>;
 
+/// Where the config lives: the flash, or a RAM buffer standing in for it when the flash can't be used
+#[cfg(feature = "ram-fallback")]
+pub enum StorageBackend {
+    Flash(ConcreteFlash),
+    /// Volatile, everything stored is lost on reboot. See `StorageManager::ram_fallback`.
+    RamFallback(RamFlash),
+}
+
+/// A RAM buffer that behaves like the storage area of the flash: erasing sets bytes to 0xFF
+/// and writing can only clear bits, so sequential-storage sees the same data it would in flash
+#[cfg(feature = "ram-fallback")]
+pub struct RamFlash(&'static mut [u8; MAP_FLASH_SIZE]);
+
+// Set once the storage manager runs on RAM, for `status`
+#[cfg(feature = "ram-fallback")]
+static RAM_FALLBACK: AtomicBool = AtomicBool::new(false);
+
+#[cfg(feature = "ram-fallback")]
+impl RamFlash {
+    fn range(&mut self, offset: u32, len: usize) -> Result<&mut [u8], embassy_stm32::flash::Error> {
+        let start = offset as usize;
+        self.0.get_mut(start..start + len).ok_or(embassy_stm32::flash::Error::Size)
+    }
+}
+
+#[cfg(feature = "ram-fallback")]
+impl ErrorType for StorageBackend {
+    type Error = embassy_stm32::flash::Error;
+}
+
+#[cfg(feature = "ram-fallback")]
+impl ReadNorFlash for StorageBackend {
+    const READ_SIZE: usize = ConcreteFlash::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        match self {
+            StorageBackend::Flash(flash) => flash.read(offset, bytes),
+            StorageBackend::RamFallback(ram) => {
+                bytes.copy_from_slice(ram.range(offset, bytes.len())?);
+                Ok(())
+            }
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        match self {
+            StorageBackend::Flash(flash) => flash.capacity(),
+            StorageBackend::RamFallback(_) => MAP_FLASH_SIZE,
+        }
+    }
+}
+
+#[cfg(feature = "ram-fallback")]
+impl NorFlash for StorageBackend {
+    const WRITE_SIZE: usize = ConcreteFlash::WRITE_SIZE;
+    const ERASE_SIZE: usize = ConcreteFlash::ERASE_SIZE;
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        match self {
+            StorageBackend::Flash(flash) => flash.write(offset, bytes),
+            StorageBackend::RamFallback(ram) => {
+                for (cell, byte) in ram.range(offset, bytes.len())?.iter_mut().zip(bytes) {
+                    *cell &= byte;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        match self {
+            StorageBackend::Flash(flash) => flash.erase(from, to),
+            StorageBackend::RamFallback(ram) => {
+                ram.range(from, to.saturating_sub(from) as usize)?.fill(0xFF);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(feature = "ram-fallback")]
+impl ConcreteStorageManager {
+    /// A store in RAM for when the flash one can't be set up, so the device keeps running and
+    /// can be diagnosed over the CLI. It works like the flash store, but loses everything on reboot.
+    pub fn ram_fallback(error: StorageInitError) -> Self {
+        static RAM_STORE: StaticCell<[u8; MAP_FLASH_SIZE]> = StaticCell::new();
+        defmt::error!("!!! Storage unusable: {}. Running on RAM, the config is lost on reboot !!!", error);
+        RAM_FALLBACK.store(true, Ordering::Relaxed);
+        let ram = RamFlash(RAM_STORE.init([0xFF; MAP_FLASH_SIZE]));
+        Self::with_range(BlockingAsync::new(StorageBackend::RamFallback(ram)), 0..MAP_FLASH_SIZE as u32)
+    }
+}
+
+/// Whether the config is kept in RAM because the flash couldn't be used, see `StorageManager::ram_fallback`
+#[cfg(feature = "ram-fallback")]
+pub fn ram_fallback_active() -> bool {
+    RAM_FALLBACK.load(Ordering::Relaxed)
+}
+
 // Global instance of the storage manager with concrete type
 pub static STORAGE_MANAGER: StaticCell<Mutex<CriticalSectionRawMutex, ConcreteStorageManager>> = StaticCell::new();
 
 // =========================================================================
 // IMPORTANT NOTE ON 'Corrupted' ERROR:
-// If you encounter `Error saving counter: Corrupted`, it usually means
-// the flash area defined by `MAP_FLASH_RANGE` was not erased before the
-// first write attempt by `sequential-storage`.
-//
-// To fix this during development:
-// 1. Use the `erase_map_area` function provided below.
-// 2. Call it ONCE in your `main.rs` *before* the `StorageManager` is
-//    put into the `STORAGE_MANAGER` mutex. See example comment in `main.rs`.
-// 3. After the first successful run where the area is erased,
-//    **COMMENT OUT THE CALL TO `erase_map_area` in `main.rs`**
-//    to prevent erasing your stored data on every boot.
+// `Corrupted` means the flash area defined by `MAP_FLASH_RANGE` doesn't hold
+// a valid map, usually because it was never erased (the unity hex built by
+// `just build` fills it with 0x00). `initialize` runs at every boot and
+// erases the area only in that case, so valid data survives a reset.
+// The `corrupt` command puts the area in that state to test the recovery.
 // =========================================================================
 
 impl<F: AsyncNorFlash> StorageManager<F>
 where
     F::Error: Format // Ensure the flash error type can be formatted by defmt
 {
-    pub fn new(flash: F) -> Self {
+    /// The default store in MAP_FLASH_RANGE. Refuses to use it if the firmware reaches into the range:
+    /// memory.x spans the whole flash, so the linker doesn't catch a firmware that has grown too large.
+    pub fn new(flash: F) -> Result<Self, StorageInitError> {
+        let storage_start = FLASH_BASE as u32 + MAP_FLASH_RANGE.start;
+        let firmware_end = addr_of!(__veneer_limit) as u32;
+        if storage_start < firmware_end {
+            return Err(StorageInitError::OverlapsFirmware { storage_start, firmware_end });
+        }
+        Ok(Self::with_range(flash, MAP_FLASH_RANGE))
+    }
+
+    /// A store in another flash range, e.g. for frequently written telemetry that shouldn't
+    /// wear out the pages holding the config. The range must be page-aligned, span at least
+    /// two pages and not overlap any other store. Every store needs its own `flash`: share
+    /// the peripheral with embassy-embedded-hal's `flash::partition::Partition`, which also
+    /// takes care of the offset, and give each store the partition's full range.
+    pub fn with_range(flash: F, range: Range<u32>) -> Self {
         Self {
             flash,
-            // Ensure buffer size matches struct definition
-            data_buffer: [0u8; 64],
+            range,
+            data_buffer: [0u8; DATA_BUFFER_SIZE],
+            #[cfg(feature = "diagnostics")]
+            write_counts: LinearMap::new(),
+            #[cfg(feature = "diagnostics")]
+            other_writes: 0,
+            #[cfg(feature = "write-limit")]
+            window_start: Instant::now(),
+            #[cfg(feature = "write-limit")]
+            window_writes: 0,
+            #[cfg(feature = "lkg")]
+            config_changed: false,
         }
     }
 
     /// Erases the entire flash area designated for the storage map.
-    /// Call this ONCE during development setup if you get `Corrupted` errors.
     pub async fn erase_map_area(&mut self) -> Result<(), StorageError<F::Error>> {
-        info!("Erasing map storage area (relative range): {:x}..{:x}", MAP_FLASH_RANGE.start, MAP_FLASH_RANGE.end);
+        info!("Erasing map storage area (relative range): {:x}..{:x}", self.range.start, self.range.end);
         // Use sequential_storage's erase_all for the map range
-        sequential_storage::erase_all(&mut self.flash, MAP_FLASH_RANGE.clone()).await?;
+        sequential_storage::erase_all(&mut self.flash, self.range.clone()).await?;
         info!("Map storage area erased successfully.");
         Ok(())
     }
 
+    /// Marks every page of the map as closed, a state sequential-storage can't repair: the next
+    /// read fails with `Corrupted`, so the next boot takes the erase in `initialize`.
+    /// For testing that recovery only, everything stored is lost.
+    #[cfg(feature = "corrupt")]
+    pub async fn corrupt_map_area(&mut self) -> Result<(), StorageError<F::Error>> {
+        // A page is marked by zeroing its first and last word. With every page marked there is
+        // no open page left, the same as the zero-filled area of a fresh unity hex.
+        let marker = [0u8; WRITE_SIZE];
+        for page in self.range.clone().step_by(F::ERASE_SIZE) {
+            for offset in [page, page + (F::ERASE_SIZE - WRITE_SIZE) as u32] {
+                self.flash.write(offset, &marker).await.map_err(|value| StorageError::Storage { value })?;
+            }
+        }
+        defmt::warn!("Storage area corrupted on request");
+        Ok(())
+    }
+
+    /// Dry run of `erase_map_area`: the number of live keys and the bytes they take up,
+    /// all of which an erase would remove. Only reads the flash.
+    pub async fn erase_preview(&mut self) -> Result<(usize, usize), StorageError<F::Error>> {
+        let (mut keys, mut bytes) = (0, 0);
+        self.entry_sizes(|_, size| {
+            keys += 1;
+            bytes += size;
+        })
+        .await?;
+        Ok((keys, bytes))
+    }
 
     // Initialize storage and load existing state if available
     pub async fn initialize(&mut self) -> Result<AppState, ()> {
         let mut state = AppState::default();
 
-        // --- Optional: Erase call location ---
-        // You *could* call erase here, but it's generally better to do it
-        // explicitly once in main.rs during setup to avoid erasing on every init.
-        // match self.erase_map_area().await {
-        //      Ok(_) => info!("Storage area erased during init."),
-        //      Err(e) => info!("Failed to erase during init: {}", defmt::Debug2Format(&e)),
-        // }
-        // --- End Optional Erase ---
-
-        match self.get_counter().await {
+        match self.fetch_value::<u32>(KEY_COUNTER).await {
             Ok(Some(counter)) => {
                 info!("Loaded counter: {}", counter);
                 state.counter = counter;
@@ -126,6 +1194,13 @@ where
             Ok(None) => {
                 info!("No counter found in storage, using default.");
             }
+            // No valid map, see the note on `Corrupted` above. Erased so it can be written,
+            // this is the only place that erases without being asked to.
+            Err(StorageError::Corrupted { .. }) => {
+                info!("Storage area is corrupted, erasing it");
+                self.erase_map_area().await.map_err(drop)?;
+                return Ok(state);
+            }
             Err(_) => {
                  info!("Error reading counter, using default.");
                  // Optionally return Err here if loading is critical
@@ -148,43 +1223,14 @@ where
         Ok(state)
     }
 
-    // Get counter value from storage
-    pub async fn get_counter(&mut self) -> Result<Option<u32>, ()> {
-        match fetch_item::<u32, u32, _>(
-            &mut self.flash,
-            MAP_FLASH_RANGE.clone(), // Uses the corrected relative range
-            &mut NoCache::new(),
-            &mut self.data_buffer,
-            &KEY_COUNTER,
-        )
-        .await
-        {
-            Ok(value) => Ok(value),
-            Err(e) => {
-                // Log the specific error for debugging reads too
-                info!("Error reading counter: {}", defmt::Debug2Format(&e));
-                Err(())
-            }
-        }
-    }
-
     // Save counter value to storage
     pub async fn set_counter(&mut self, counter: u32) -> Result<(), ()> {
         info!("Saving counter: {}", counter);
-        match store_item(
-            &mut self.flash,
-            MAP_FLASH_RANGE.clone(), // Uses the corrected relative range
-            &mut NoCache::new(),
-            &mut self.data_buffer,
-            &KEY_COUNTER,
-            &counter,
-        )
-        .await
-        {
+        match self.store_value(KEY_COUNTER, &counter).await {
             Ok(_) => Ok(()),
             Err(e) => {
                 // Keep the detailed error logging for Corrupted/Size errors
-                info!("Error saving counter: {}", defmt::Debug2Format(&e));
+                info!("Error saving counter: {}", e);
                 Err(())
             }
         }
@@ -192,19 +1238,11 @@ where
 
     // Get mode value from storage
     pub async fn get_mode(&mut self) -> Result<Option<u8>, ()> {
-        match fetch_item::<u32, u8, _>(
-            &mut self.flash,
-            MAP_FLASH_RANGE.clone(), // Uses the corrected relative range
-            &mut NoCache::new(),
-            &mut self.data_buffer,
-            &KEY_MODE,
-        )
-        .await
-        {
+        match self.fetch_value::<u8>(KEY_MODE).await {
             Ok(value) => Ok(value),
             Err(e) => {
                 // Log the specific error for debugging reads too
-                info!("Error reading mode: {}", defmt::Debug2Format(&e));
+                info!("Error reading mode: {}", e);
                 Err(())
             }
         }
@@ -213,22 +1251,693 @@ where
     // Save mode value to storage
     pub async fn set_mode(&mut self, mode: u8) -> Result<(), ()> {
         info!("Saving mode: {}", mode);
-        match store_item(
+        match self.store_value(KEY_MODE, &mode).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                // Keep the detailed error logging
+                info!("Error saving mode: {}", e);
+                Err(())
+            }
+        }
+    }
+
+    // Store raw bytes under `key`. All typed values go through this single (non-generic)
+    // path, so `store_item` is only instantiated once, which saves a lot of flash.
+    // With the `audit` feature every successful write is also recorded in the audit log,
+    // with `write-limit` writes beyond the rate limit are refused.
+    async fn store_raw(&mut self, key: u32, bytes: &[u8]) -> Result<(), WriteError<F::Error>> {
+        #[cfg(feature = "write-limit")]
+        self.check_rate_limit(key)?;
+        #[cfg(feature = "diagnostics")]
+        self.count_write(key);
+        self.write_raw(key, bytes).await?;
+        #[cfg(feature = "lkg")]
+        if LKG_CONFIG.contains(&key) {
+            self.config_changed = true;
+        }
+        #[cfg(feature = "audit")]
+        self.audit(key).await;
+        Ok(())
+    }
+
+    // Write `bytes` under `key`, flash driver errors are retried, see FLASH_WRITE_RETRIES
+    async fn write_raw(&mut self, key: u32, bytes: &[u8]) -> Result<(), StorageError<F::Error>> {
+        let mut attempt = 0;
+        loop {
+            let result = store_item::<u32, &[u8], _>(
+                &mut self.flash,
+                self.range.clone(),
+                &mut NoCache::new(),
+                &mut self.data_buffer,
+                &key,
+                &bytes,
+            )
+            .await;
+            match result {
+                Err(StorageError::Storage { value }) if attempt < FLASH_WRITE_RETRIES => {
+                    errstat::count(ErrCounter::FlashWrite);
+                    attempt += 1;
+                    info!("Flash error writing key {}: {}, retry {}/{}", key, value, attempt, FLASH_WRITE_RETRIES);
+                    Timer::after(FLASH_RETRY_DELAY).await;
+                }
+                result => {
+                    if let Err(StorageError::Storage { .. }) = result {
+                        errstat::count(ErrCounter::FlashWrite);
+                    }
+                    return result;
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "write-limit")]
+    // Count a write against the rate limit, failing once the limit of the current window is used up
+    fn check_rate_limit(&mut self, key: u32) -> Result<(), WriteError<F::Error>> {
+        if self.window_start.elapsed() >= WRITE_LIMIT_WINDOW {
+            self.window_start = Instant::now();
+            self.window_writes = 0;
+        }
+        if self.window_writes >= WRITE_LIMIT {
+            defmt::warn!("Write to key {} refused, more than {} writes in {}s", key, WRITE_LIMIT, WRITE_LIMIT_WINDOW.as_secs());
+            return Err(WriteError::RateLimited);
+        }
+        self.window_writes += 1;
+        Ok(())
+    }
+
+    #[cfg(feature = "audit")]
+    // Append a change of `key` to the audit log. The log is written with `write_raw`,
+    // so writing it isn't audited itself. A failure only loses the record, not the change.
+    async fn audit(&mut self, key: u32) {
+        let mut log = self.read_audit().await.unwrap_or_default();
+        log.push(AuditRecord { key, ts: Instant::now().as_secs() as i64 });
+        let mut buf = [0u8; AuditLog::SERIALIZED_SIZE];
+        let result = match log.serialize_into(&mut buf) {
+            Ok(len) => self.write_raw(KEY_AUDIT_LOG, &buf[..len]).await,
+            Err(e) => Err(StorageError::SerializationError(e)),
+        };
+        if let Err(e) = result {
+            info!("Error saving audit record for key {}: {}", key, e);
+        }
+    }
+
+    #[cfg(feature = "audit")]
+    /// Read the audit log of config changes, an absent log is returned as empty
+    pub async fn read_audit(&mut self) -> Result<AuditLog, ()> {
+        match self.fetch_value::<AuditLog>(KEY_AUDIT_LOG).await {
+            Ok(log) => Ok(log.unwrap_or_default()),
+            Err(e) => {
+                info!("Error reading audit log: {}", e);
+                Err(())
+            }
+        }
+    }
+
+    #[cfg(feature = "diagnostics")]
+    // Count a write attempt to `key`, falling back to the "other" bucket once all slots are taken
+    fn count_write(&mut self, key: u32) {
+        if let Some(count) = self.write_counts.get_mut(&key) {
+            *count += 1;
+        } else if self.write_counts.insert(key, 1).is_err() {
+            self.other_writes += 1;
+        }
+    }
+
+    #[cfg(feature = "diagnostics")]
+    /// Report the number of writes per key since boot. These counts live in RAM only.
+    /// Writes to keys beyond the first `MAX_COUNTED_KEYS` are reported together with `None` as the key.
+    pub fn write_counts(&self, mut f: impl FnMut(Option<u32>, u32)) {
+        for (&key, &count) in self.write_counts.iter() {
+            f(Some(key), count);
+        }
+        if self.other_writes > 0 {
+            f(None, self.other_writes);
+        }
+    }
+
+    // Fetch the raw bytes stored under `key`, counterpart of `store_raw`
+    async fn fetch_raw(&mut self, key: u32) -> Result<Option<&[u8]>, StorageError<F::Error>> {
+        fetch_item::<u32, &[u8], _>(
             &mut self.flash,
-            MAP_FLASH_RANGE.clone(), // Uses the corrected relative range
+            self.range.clone(),
             &mut NoCache::new(),
             &mut self.data_buffer,
-            &KEY_MODE,
-            &mode,
+            &key,
         )
         .await
-        {
-            Ok(_) => Ok(()),
+        .inspect_err(|e| {
+            if let StorageError::Storage { .. } = e {
+                errstat::count(ErrCounter::FlashRead);
+            }
+        })
+    }
+
+    // Serialize a value and store it through `store_raw`
+    async fn store_value<V: for<'a> Value<'a>>(&mut self, key: u32, value: &V) -> Result<(), WriteError<F::Error>> {
+        let mut buf = [0u8; MAX_VALUE_SIZE];
+        let len = value.serialize_into(&mut buf).map_err(StorageError::SerializationError)?;
+        self.store_raw(key, &buf[..len]).await
+    }
+
+    // Fetch a value through `fetch_raw` and deserialize it
+    async fn fetch_value<V: for<'a> Value<'a>>(&mut self, key: u32) -> Result<Option<V>, StorageError<F::Error>> {
+        self.fetch_raw(key)
+            .await?
+            .map(V::deserialize_from)
+            .transpose()
+            .map_err(StorageError::SerializationError)
+    }
+
+    /// Store an opaque blob under `key` as-is, without any serialization. The key must be one of USER_KEYS.
+    /// Blobs that don't fit in `DATA_BUFFER_SIZE` along with the key are rejected with `BufferTooSmall`.
+    pub async fn set_bytes(&mut self, key: u32, data: &[u8]) -> Result<(), WriteError<F::Error>> {
+        if !USER_KEYS.contains(&key) {
+            return Err(WriteError::ReservedKey);
+        }
+        info!("Saving {} bytes under key {}", data.len(), key);
+        self.store_raw(key, data).await
+    }
+
+    /// Copy the blob stored under `key` into `out`, returning its length.
+    /// If `out` is too short, `BufferTooSmall` reports the size that's needed.
+    pub async fn get_bytes(&mut self, key: u32, out: &mut [u8]) -> Result<Option<usize>, StorageError<F::Error>> {
+        match self.fetch_raw(key).await? {
+            Some(bytes) if bytes.len() > out.len() => Err(StorageError::BufferTooSmall(bytes.len())),
+            Some(bytes) => {
+                out[..bytes.len()].copy_from_slice(bytes);
+                Ok(Some(bytes.len()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Store a number of any supported type under `key`, which must be one of USER_KEYS.
+    /// The firmware's own keys have a fixed type, a number of another width would break their getters.
+    pub async fn set_num(&mut self, key: u32, value: NumValue) -> Result<(), WriteError<F::Error>> {
+        if !USER_KEYS.contains(&key) {
+            return Err(WriteError::ReservedKey);
+        }
+        info!("Saving {} under key {}", value, key);
+        let (buf, len) = value.to_bytes();
+        self.store_raw(key, &buf[..len]).await
+    }
+
+    /// Fetch a number stored with `set_num`.
+    /// Fails with `InvalidFormat` if the stored value is not as wide as `ty`.
+    pub async fn get_num(&mut self, key: u32, ty: NumType) -> Result<Option<NumValue>, StorageError<F::Error>> {
+        match self.fetch_raw(key).await? {
+            Some(bytes) => NumValue::from_bytes(ty, bytes)
+                .map(Some)
+                .ok_or(StorageError::SerializationError(SerializationError::InvalidFormat)),
+            None => Ok(None),
+        }
+    }
+
+    /// Report the flash space taken by every live key: item header, key bytes and value,
+    /// padded to the flash word size. Superseded copies of a key still in flash aren't counted.
+    /// Only a store larger than MAP_FLASH_RANGE can have more than MAX_LIVE_KEYS keys,
+    /// the ones beyond that are left out with a warning.
+    pub async fn entry_sizes(&mut self, mut f: impl FnMut(u32, usize)) -> Result<(), StorageError<F::Error>> {
+        let overhead = sequential_storage::item_overhead_size::<F>() as usize;
+        // Items come oldest first, so the last size seen for a key is the live one
+        let mut sizes: LinearMap<u32, usize, MAX_LIVE_KEYS> = LinearMap::new();
+        let mut cache = NoCache::new();
+        let mut iter = fetch_all_items::<u32, _, _>(
+            &mut self.flash,
+            self.range.clone(),
+            &mut cache,
+            &mut self.data_buffer,
+        )
+        .await?;
+        while let Some((key, value)) = iter.next::<u32, &[u8]>(&mut self.data_buffer).await? {
+            let size = overhead + (KEY_SIZE + value.len()).next_multiple_of(F::WRITE_SIZE);
+            if sizes.insert(key, size).is_err() {
+                defmt::warn!("More than {} keys, key {} not counted", MAX_LIVE_KEYS, key);
+            }
+        }
+        for (&key, &size) in sizes.iter() {
+            f(key, size);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "diagnostics")]
+    /// Read-only integrity check: decode the live value of every key in VALUE_SIZES (and its
+    /// last known good copy) and call `corrupt` for each one that doesn't decode. Returns the number
+    /// of live entries and of corrupt ones. Keys of `set_num`/`set_bytes` and blob chunks can't be
+    /// checked as their type isn't known.
+    pub async fn fsck(&mut self, mut corrupt: impl FnMut(u32)) -> Result<(usize, usize), StorageError<F::Error>> {
+        let mut entries = 0;
+        self.entry_sizes(|_, _| entries += 1).await?;
+
+        let mut bad = 0;
+        for &(key, _) in VALUE_SIZES {
+            #[cfg(feature = "blob")]
+            if key == BLOB_CHUNK_KEYS {
+                continue;
+            }
+            #[cfg(feature = "lkg")]
+            let copy = LKG_CONFIG.contains(&key).then_some(LKG_KEYS | key);
+            #[cfg(not(feature = "lkg"))]
+            let copy = None;
+            for stored in [Some(key), copy].into_iter().flatten() {
+                if self.fetch_raw(stored).await?.is_some_and(|bytes| !decodes(key, bytes)) {
+                    info!("Key {} is corrupt", stored);
+                    corrupt(stored);
+                    bad += 1;
+                }
+            }
+        }
+        Ok((entries, bad))
+    }
+
+    /// Write the typed keys in EXPORT_KEYS as a JSON object, e.g.
+    /// `{"counter":5,"mode":1,"flags":{"echo":true,"lf":false},"prompt":">","role":"sensor"}`.
+    /// Settings that aren't stored are written with their default from SETTINGS_DEFAULTS,
+    /// other keys are left out until they're stored.
+    /// Blobs and `set_num` values have no known type and aren't exported.
+    pub async fn export_json<W: uWrite + ?Sized>(&mut self, w: &mut W) -> Result<(), ()> {
+        let mut first = true;
+        for &(key, name) in EXPORT_KEYS {
+            let stored = self.fetch_raw(key).await.map_err(|e| {
+                info!("Error reading key {} for export: {}", key, e);
+            })?;
+            let default = SETTINGS_DEFAULTS.iter().find(|&&(k, _)| k == key).map(|&(_, bytes)| bytes);
+            if let Some(bytes) = stored.or(default) {
+                write_json_name(w, &mut first, name).and_then(|_| write_json_value(w, key, bytes)).map_err(drop)?;
+            }
+        }
+        // Never empty, the settings have defaults
+        w.write_char('}').map_err(drop)
+    }
+
+    // Get the event log from storage
+    pub async fn get_event_log(&mut self) -> Result<Option<EventLog>, ()> {
+        self.fetch_value(KEY_EVENT_LOG).await.map_err(|e| {
+            info!("Error reading event log: {}", e);
+        })
+    }
+
+    // Save the event log to storage
+    pub async fn set_event_log(&mut self, log: &EventLog) -> Result<(), ()> {
+        info!("Saving event log ({} events)", log.len());
+        self.store_value(KEY_EVENT_LOG, log).await.map_err(|e| {
+            info!("Error saving event log: {}", e);
+        })
+    }
+
+    // Get the recent sensor samples stored by `telemetry::record`
+    #[cfg(feature = "telemetry")]
+    pub async fn get_telemetry(&mut self) -> Result<Option<RecentSamples>, ()> {
+        self.fetch_value(KEY_TELEMETRY).await.map_err(|e| {
+            info!("Error reading telemetry: {}", e);
+        })
+    }
+
+    // Save the recent sensor samples
+    #[cfg(feature = "telemetry")]
+    pub async fn set_telemetry(&mut self, recent: &RecentSamples) -> Result<(), ()> {
+        info!("Saving telemetry ({} samples)", recent.len());
+        self.store_value(KEY_TELEMETRY, recent).await.map_err(|e| {
+            info!("Error saving telemetry: {}", e);
+        })
+    }
+
+    // Get the sensor sampling interval in seconds, the default one when it was never set
+    #[cfg(feature = "telemetry")]
+    pub async fn get_sensors_interval(&mut self) -> Result<u32, ()> {
+        match self.fetch_value::<u32>(KEY_SENSORS_INTERVAL).await {
+            Ok(value) => Ok(value.unwrap_or(DEFAULT_SENSORS_INTERVAL)),
             Err(e) => {
-                // Keep the detailed error logging
-                info!("Error saving mode: {}", defmt::Debug2Format(&e));
+                info!("Error reading sensors interval: {}", e);
+                Err(())
+            }
+        }
+    }
+
+    // Save the sensor sampling interval, the sampler picks it up right away
+    #[cfg(feature = "telemetry")]
+    pub async fn set_sensors_interval(&mut self, secs: u32) -> Result<(), ()> {
+        info!("Saving sensors interval: {}", secs);
+        self.store_value(KEY_SENSORS_INTERVAL, &secs).await.map_err(|e| {
+            info!("Error saving sensors interval: {}", e);
+        })?;
+        SENSORS_INTERVAL_CHANGED.signal(secs);
+        Ok(())
+    }
+
+    // Get the calibration table from storage
+    pub async fn get_cal_table(&mut self) -> Result<Option<CalTable>, ()> {
+        self.fetch_value(KEY_CALIBRATION).await.map_err(|e| {
+            info!("Error reading calibration: {}", e);
+        })
+    }
+
+    // Save the calibration table to storage
+    pub async fn set_cal_table(&mut self, table: &CalTable) -> Result<(), ()> {
+        info!("Saving calibration table");
+        self.store_value(KEY_CALIBRATION, table).await.map_err(|e| {
+            info!("Error saving calibration: {}", e);
+        })
+    }
+
+    // Get the network settings from storage
+    pub async fn get_net_config(&mut self) -> Result<Option<NetConfig>, ()> {
+        self.fetch_value(KEY_NET_CONFIG).await.map_err(|e| {
+            info!("Error reading network config: {}", e);
+        })
+    }
+
+    // Save the network settings to storage
+    pub async fn set_net_config(&mut self, config: &NetConfig) -> Result<(), ()> {
+        info!("Saving network config: {}", config);
+        self.store_value(KEY_NET_CONFIG, config).await.map_err(|e| {
+            info!("Error saving network config: {}", e);
+        })
+    }
+
+    // Get the feature flags bitfield, all flags are off when nothing is stored yet
+    pub async fn get_flags(&mut self) -> Result<u32, ()> {
+        match self.fetch_value::<u32>(KEY_FLAGS).await {
+            Ok(value) => Ok(value.unwrap_or(0)),
+            Err(e) => {
+                info!("Error reading flags: {}", e);
+                Err(())
+            }
+        }
+    }
+
+    // Turn a single feature flag on or off, leaving the other bits alone
+    pub async fn set_flag(&mut self, bit: u8, on: bool) -> Result<(), ()> {
+        let old = self.get_flags().await?;
+        let flags = if on { old | (1 << bit) } else { old & !(1 << bit) };
+        if flags == old {
+            return Ok(());
+        }
+        info!("Saving flags: {:x}", flags);
+        self.store_value(KEY_FLAGS, &flags).await.map_err(|e| {
+            info!("Error saving flags: {}", e);
+        })?;
+        FLAGS_CHANGED.signal(flags);
+        Ok(())
+    }
+
+    // Write the defaults of all settings: flags, prompt, role and the maintenance interval.
+    // Counters, logs, calibration and the network config are kept. Returns the number of keys written.
+    // Not inlined: the loop would otherwise end up in the CLI session's state machine,
+    // which made it about 600 bytes larger
+    #[inline(never)]
+    pub async fn reset_settings(&mut self) -> Result<usize, ()> {
+        info!("Resetting settings to defaults");
+        for &(key, bytes) in SETTINGS_DEFAULTS {
+            self.store_raw(key, bytes).await.map_err(|e| {
+                info!("Error resetting key {}: {}", key, e);
+            })?;
+        }
+        FLAGS_CHANGED.signal(0);
+        #[cfg(feature = "telemetry")]
+        SENSORS_INTERVAL_CHANGED.signal(DEFAULT_SENSORS_INTERVAL);
+        Ok(SETTINGS_DEFAULTS.len())
+    }
+
+    // Get the device role, the default one when nothing (or an unknown role) is stored
+    pub async fn get_role(&mut self) -> Result<DeviceRole, ()> {
+        match self.fetch_value::<u8>(KEY_ROLE).await {
+            Ok(value) => Ok(value.and_then(DeviceRole::from_u8).unwrap_or_default()),
+            Err(e) => {
+                info!("Error reading role: {}", e);
+                Err(())
+            }
+        }
+    }
+
+    // Save the device role
+    pub async fn set_role(&mut self, role: DeviceRole) -> Result<(), ()> {
+        info!("Saving role: {}", role);
+        self.store_value(KEY_ROLE, &(role as u8)).await.map_err(|e| {
+            info!("Error saving role: {}", e);
+        })
+    }
+
+    // Whether provisioning has been completed, false until `set_commissioned(true)`
+    pub async fn is_commissioned(&mut self) -> Result<bool, ()> {
+        match self.fetch_value::<u8>(KEY_COMMISSIONED).await {
+            Ok(value) => Ok(value == Some(1)),
+            Err(e) => {
+                info!("Error reading commissioning state: {}", e);
+                Err(())
+            }
+        }
+    }
+
+    // Mark provisioning as complete, or undo that to send the unit through provisioning again
+    pub async fn set_commissioned(&mut self, commissioned: bool) -> Result<(), ()> {
+        info!("Saving commissioned: {}", commissioned);
+        self.store_value(KEY_COMMISSIONED, &(commissioned as u8)).await.map_err(|e| {
+            info!("Error saving commissioning state: {}", e);
+        })
+    }
+
+    // Get the CLI prompt, `None` when it was never set
+    pub async fn get_prompt(&mut self) -> Result<Option<String<PROMPT_MAX_LEN>>, ()> {
+        match self.fetch_raw(KEY_PROMPT).await {
+            Ok(bytes) => Ok(bytes.and_then(|b| core::str::from_utf8(b).ok()).and_then(|s| String::try_from(s).ok())),
+            Err(e) => {
+                info!("Error reading prompt: {}", e);
+                Err(())
+            }
+        }
+    }
+
+    // Save the CLI prompt
+    pub async fn set_prompt(&mut self, prompt: &str) -> Result<(), ()> {
+        info!("Saving prompt: {}", prompt);
+        self.store_raw(KEY_PROMPT, prompt.as_bytes()).await.map_err(|e| {
+            info!("Error saving prompt: {}", e);
+        })
+    }
+
+    // Get the total power-on hours, 0 on the first run when nothing is stored yet
+    pub async fn get_total_hours(&mut self) -> Result<u32, ()> {
+        match self.fetch_value::<u32>(KEY_TOTAL_HOURS).await {
+            Ok(value) => Ok(value.unwrap_or(0)),
+            Err(e) => {
+                info!("Error reading total hours: {}", e);
                 Err(())
             }
         }
     }
+
+    // Save the total power-on hours
+    pub async fn set_total_hours(&mut self, hours: u32) -> Result<(), ()> {
+        info!("Saving total hours: {}", hours);
+        self.store_value(KEY_TOTAL_HOURS, &hours).await.map_err(|e| {
+            info!("Error saving total hours: {}", e);
+        })
+    }
+
+    // Get the maintenance interval in hours, the default one when it was never set
+    #[cfg(feature = "maintenance")]
+    pub async fn get_maint_interval(&mut self) -> Result<u32, ()> {
+        match self.fetch_value::<u32>(KEY_MAINT_INTERVAL).await {
+            Ok(value) => Ok(value.unwrap_or(DEFAULT_MAINT_INTERVAL)),
+            Err(e) => {
+                info!("Error reading maintenance interval: {}", e);
+                Err(())
+            }
+        }
+    }
+
+    // Save the maintenance interval, applies from the next `maintenance_done`
+    #[cfg(feature = "maintenance")]
+    pub async fn set_maint_interval(&mut self, hours: u32) -> Result<(), ()> {
+        info!("Saving maintenance interval: {}", hours);
+        self.store_value(KEY_MAINT_INTERVAL, &hours).await.map_err(|e| {
+            info!("Error saving maintenance interval: {}", e);
+        })
+    }
+
+    // Power-on hours left until the next maintenance, negative once it's overdue.
+    // `None` until the first `maintenance_done`, a unit fresh from the factory isn't due.
+    #[cfg(feature = "maintenance")]
+    pub async fn maintenance_remaining(&mut self) -> Result<Option<i32>, ()> {
+        let hours = self.get_total_hours().await?;
+        match self.fetch_value::<u32>(KEY_MAINT_DUE).await {
+            Ok(due) => Ok(due.map(|due| due.wrapping_sub(hours) as i32)),
+            Err(e) => {
+                info!("Error reading maintenance due time: {}", e);
+                Err(())
+            }
+        }
+    }
+
+    // Record a maintenance: the next one is due one interval from now. Returns the interval.
+    #[cfg(feature = "maintenance")]
+    pub async fn maintenance_done(&mut self) -> Result<u32, ()> {
+        let interval = self.get_maint_interval().await?;
+        let due = self.get_total_hours().await?.saturating_add(interval);
+        info!("Next maintenance at {} hours", due);
+        self.store_value(KEY_MAINT_DUE, &due).await.map_err(|e| {
+            info!("Error saving maintenance due time: {}", e);
+        })?;
+        Ok(interval)
+    }
+
+    /// Save the current config as the last known good one, see LKG_CONFIG. Returns the number of keys written.
+    /// Copies that are already up to date aren't rewritten, so marking every boot costs no flash wear
+    /// as long as the config doesn't change. A key that was never set has no copy.
+    #[cfg(feature = "lkg")]
+    pub async fn mark_good(&mut self) -> Result<usize, ()> {
+        info!("Marking the config as last known good");
+        match self.copy_config(0, LKG_KEYS).await {
+            Ok((_, written)) => Ok(written),
+            Err(e) => {
+                info!("Error saving last known good config: {}", e);
+                Err(())
+            }
+        }
+    }
+
+    /// Put the last known good config back. Returns the number of keys it has a copy of
+    /// and how many of them differed and were written. Keys that were first set after
+    /// the last `mark_good` keep their value, they have no copy to go back to.
+    /// Only the boot health decides what's good: a bad config that still lets the device
+    /// run through LKG_MARK_AFTER_MINUTES after a reboot is marked good, and from then on
+    /// the config before it can't be restored anymore.
+    #[cfg(feature = "lkg")]
+    pub async fn restore_good(&mut self) -> Result<(usize, usize), ()> {
+        info!("Restoring the last known good config");
+        self.copy_config(LKG_KEYS, 0).await.map_err(|e| {
+            info!("Error restoring last known good config: {}", e);
+        })
+    }
+
+    // Copy each LKG_CONFIG key from `from | key` to `to | key` where they differ, returns the number
+    // of keys found and written. Restored config goes through `store_raw` like any other config change,
+    // the copies are written with `write_raw` so they skip the rate limiter and the audit log.
+    #[cfg(feature = "lkg")]
+    async fn copy_config(&mut self, from: u32, to: u32) -> Result<(usize, usize), WriteError<F::Error>> {
+        let mut buf = [0u8; MAX_VALUE_SIZE];
+        let (mut found, mut written) = (0, 0);
+        for &key in LKG_CONFIG {
+            let Some(bytes) = self.fetch_raw(from | key).await? else {
+                continue;
+            };
+            let value = &mut buf[..bytes.len()];
+            value.copy_from_slice(bytes);
+            found += 1;
+            if self.fetch_raw(to | key).await? == Some(value) {
+                continue;
+            }
+            if to == 0 {
+                self.store_raw(key, value).await?;
+            } else {
+                self.write_raw(to | key, value).await?;
+            }
+            written += 1;
+        }
+        Ok((found, written))
+    }
+
+    /// Start writing the blob under `key`, the data follows with `write_chunk`.
+    /// Dropping the writer without `commit_blob` leaves the stored blob unchanged.
+    #[cfg(feature = "blob")]
+    pub async fn begin_blob(&mut self, key: u16) -> Result<BlobWriter, StorageError<F::Error>> {
+        let bank = match self.fetch_value::<u32>(blob_header_key(key)).await? {
+            Some(header) if header & BLOB_HEADER_BANK == 0 => 1,
+            _ => 0,
+        };
+        Ok(BlobWriter { key, bank, len: 0, chunks: 0 })
+    }
+
+    /// Append `bytes`, at most BLOB_CHUNK_SIZE of them, to the blob as its next chunk.
+    /// Fails with `FullStorage` after BLOB_MAX_CHUNKS chunks, see BLOB_MAX_LEN.
+    /// Chunks are written with `write_raw`, so they skip the rate limiter and the audit log.
+    /// The header written by `commit_blob` goes through both, once per blob.
+    #[cfg(feature = "blob")]
+    pub async fn write_chunk(&mut self, writer: &mut BlobWriter, bytes: &[u8]) -> Result<(), StorageError<F::Error>> {
+        if bytes.len() > BLOB_CHUNK_SIZE {
+            return Err(StorageError::BufferTooSmall(bytes.len()));
+        }
+        if writer.chunks == BLOB_MAX_CHUNKS {
+            return Err(StorageError::FullStorage);
+        }
+        // An empty chunk would stop `read_blob` from making progress
+        if !bytes.is_empty() {
+            self.write_raw(blob_chunk_key(writer.key, writer.bank, writer.chunks), bytes).await?;
+            writer.chunks += 1;
+            writer.len += bytes.len() as u32;
+        }
+        Ok(())
+    }
+
+    /// Store the header, which makes the new blob visible. Returns its length.
+    #[cfg(feature = "blob")]
+    pub async fn commit_blob(&mut self, writer: BlobWriter) -> Result<u32, WriteError<F::Error>> {
+        info!("Committing blob {}: {} bytes in {} chunks, bank {}", writer.key, writer.len, writer.chunks, writer.bank);
+        let header = if writer.bank == 1 { writer.len | BLOB_HEADER_BANK } else { writer.len };
+        self.store_value(blob_header_key(writer.key), &header).await?;
+        Ok(writer.len)
+    }
+
+    /// Reassemble the blob under `key` into `out`, returns its length
+    #[cfg(feature = "blob")]
+    pub async fn read_blob(&mut self, key: u16, out: &mut [u8]) -> Result<Option<usize>, StorageError<F::Error>> {
+        let Some(header) = self.fetch_value::<u32>(blob_header_key(key)).await? else {
+            return Ok(None);
+        };
+        let bank = (header & BLOB_HEADER_BANK != 0) as u8;
+        let len = (header & !BLOB_HEADER_BANK) as usize;
+        if len > out.len() {
+            return Err(StorageError::BufferTooSmall(len));
+        }
+        let (mut read, mut index) = (0, 0);
+        while read < len {
+            match self.fetch_raw(blob_chunk_key(key, bank, index)).await? {
+                Some(bytes) if read + bytes.len() <= len => {
+                    out[read..read + bytes.len()].copy_from_slice(bytes);
+                    read += bytes.len();
+                    index += 1;
+                }
+                // The header promises data that isn't there
+                _ => return Err(StorageError::SerializationError(SerializationError::InvalidData)),
+            }
+        }
+        Ok(Some(len))
+    }
+}
+
+/// Accumulates the total power-on time across resets.
+/// Sub-hour time is only kept in RAM and flash is written once per full hour,
+/// so up to 59 minutes are lost on every reset in exchange for low flash wear.
+/// Also writes staged events to flash once a minute, see `eventlog::flush`.
+/// With the `lkg` feature, a boot that's still running after LKG_MARK_AFTER_MINUTES
+/// counts as healthy and its config is marked as the last known good one. A config changed
+/// during the boot (rollback included) is only marked after the next boot has run that long.
+#[embassy_executor::task]
+pub async fn uptime_task(storage: &'static Mutex<CriticalSectionRawMutex, ConcreteStorageManager>) {
+    let mut minutes = 0u32;
+    #[cfg(feature = "lkg")]
+    let mut uptime_minutes = 0u32;
+    loop {
+        Timer::after(Duration::from_secs(60)).await;
+        let mut storage = storage.lock().await;
+        eventlog::flush(&mut storage).await.ok();
+        #[cfg(feature = "lkg")]
+        {
+            uptime_minutes += 1;
+            if uptime_minutes == LKG_MARK_AFTER_MINUTES && !storage.config_changed {
+                storage.mark_good().await.ok();
+            }
+        }
+        minutes += 1;
+        if minutes < 60 {
+            continue;
+        }
+        minutes = 0;
+
+        if let Ok(hours) = storage.get_total_hours().await {
+            storage.set_total_hours(hours + 1).await.ok();
+        }
+    }
 }
@@ -0,0 +1,108 @@
+use core::cell::Cell;
+use defmt::Format;
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::Timer;
+use embedded_storage_async::nor_flash::NorFlash as AsyncNorFlash;
+use ufmt::{uDisplay, uwrite, uWrite, Formatter};
+
+use crate::calibration;
+use crate::sensor;
+use crate::storage::{ConcreteStorageManager, RingBuffer, RingItem, StorageManager, DEFAULT_SENSORS_INTERVAL, SENSORS_INTERVAL_CHANGED};
+
+// Number of samples kept in the ring stored under KEY_TELEMETRY
+pub const TELEMETRY_LEN: usize = 8;
+
+/// The recent samples as stored in flash
+pub type RecentSamples = RingBuffer<TelemetrySample, TELEMETRY_LEN>;
+
+/// One reading of the on-chip sensors, calibrated like the `sample` command shows it
+#[derive(Format, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TelemetrySample {
+    pub vdd_mv: u16,
+    pub temp_c: i16,
+}
+
+impl RingItem for TelemetrySample {
+    const SIZE: usize = 2 + 2;
+
+    fn write_to(&self, buffer: &mut [u8]) {
+        buffer[..2].copy_from_slice(&self.vdd_mv.to_le_bytes());
+        buffer[2..4].copy_from_slice(&self.temp_c.to_le_bytes());
+    }
+
+    fn read_from(buffer: &[u8]) -> Self {
+        TelemetrySample {
+            vdd_mv: u16::from_le_bytes([buffer[0], buffer[1]]),
+            temp_c: i16::from_le_bytes([buffer[2], buffer[3]]),
+        }
+    }
+}
+
+impl uDisplay for TelemetrySample {
+    fn fmt<W: uWrite + ?Sized>(&self, f: &mut Formatter<'_, W>) -> Result<(), W::Error> {
+        uwrite!(f, "VDD {} mV, {} C", self.vdd_mv, self.temp_c)
+    }
+}
+
+// Samples are pushed to a copy of the ring in RAM, which is only stored once TELEMETRY_LEN
+// new samples have come in. Every write then carries a ring of fresh samples instead of a
+// single new one. Samples not stored yet are lost on a reset.
+#[derive(Clone, Copy)]
+struct Stage {
+    // `None` until the ring has been read from flash
+    recent: Option<RecentSamples>,
+    // Samples pushed since the ring was last stored
+    unsaved: u8,
+}
+
+static STAGE: Mutex<CriticalSectionRawMutex, Cell<Stage>> = Mutex::new(Cell::new(Stage { recent: None, unsaved: 0 }));
+
+/// Take a sample and push it to the ring, storing the ring once TELEMETRY_LEN samples are unsaved.
+/// A failed store is retried with the next sample. Does nothing while the ADC isn't initialized.
+pub async fn record<F: AsyncNorFlash>(storage: &mut StorageManager<F>)
+where
+    F::Error: Format,
+{
+    let Some(sample) = sensor::sample().await else {
+        return;
+    };
+    let vdd_mv = calibration::apply(storage, sensor::CAL_CH_VDD, sample.vdd_mv).await;
+    let temp_c = calibration::apply(storage, sensor::CAL_CH_TEMP, sample.temp_c).await;
+    let mut recent = recent(storage).await;
+    recent.push(TelemetrySample {
+        vdd_mv: vdd_mv.clamp(0, u16::MAX as i32) as u16,
+        temp_c: temp_c.clamp(i16::MIN as i32, i16::MAX as i32) as i16,
+    });
+    let mut unsaved = STAGE.lock(|stage| stage.get().unsaved).saturating_add(1);
+    if unsaved as usize >= TELEMETRY_LEN && storage.set_telemetry(&recent).await.is_ok() {
+        unsaved = 0;
+    }
+    STAGE.lock(|stage| stage.set(Stage { recent: Some(recent), unsaved }));
+}
+
+/// The recent samples, including the ones not stored yet.
+/// An absent or unreadable ring in flash is taken as empty.
+pub async fn recent<F: AsyncNorFlash>(storage: &mut StorageManager<F>) -> RecentSamples
+where
+    F::Error: Format,
+{
+    match STAGE.lock(|stage| stage.get().recent) {
+        Some(recent) => recent,
+        None => storage.get_telemetry().await.ok().flatten().unwrap_or_default(),
+    }
+}
+
+/// Records a sample every sensors interval, see `record`.
+/// A new interval set by the `interval` command restarts the wait right away, no reboot needed.
+#[embassy_executor::task]
+pub async fn telemetry_task(storage: &'static embassy_sync::mutex::Mutex<CriticalSectionRawMutex, ConcreteStorageManager>) {
+    let mut interval = storage.lock().await.get_sensors_interval().await.unwrap_or(DEFAULT_SENSORS_INTERVAL);
+    loop {
+        match select(Timer::after_secs(interval as u64), SENSORS_INTERVAL_CHANGED.wait()).await {
+            Either::First(_) => record(&mut *storage.lock().await).await,
+            Either::Second(secs) => interval = secs,
+        }
+    }
+}